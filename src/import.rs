@@ -0,0 +1,199 @@
+use std::path::Path;
+
+use gltf::{buffer::Source, material::AlphaMode, mesh::Mode, Gltf, Material, Node};
+
+use crate::matrix::*;
+use crate::scene::*;
+use crate::vector;
+
+/// Load the default scene of a glTF file, either `.gltf` with separate buffer files or binary
+/// `.glb`. Every triangle of every mesh becomes a `Triangle` with the world transform of its
+/// node, and materials map their base color, metallic and roughness onto `PhysProp`.
+/// glTF's Y axis points up and its cameras look along -Z, so the scene is turned 180 degrees
+/// around X to match. Textures, cameras, lights, animations and point or line primitives
+/// are skipped, and buffers embedded as data URIs are not supported.
+pub fn load_gltf(path: &str) -> Result<Scene, String> {
+    let gltf = Gltf::open(path).map_err(|e| e.to_string())?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let mut buffers = Vec::new();
+    for buffer in gltf.buffers() {
+        let data = match buffer.source() {
+            Source::Bin => gltf.blob.clone().ok_or("Missing binary chunk")?,
+            Source::Uri(uri) if uri.starts_with("data:") => {
+                return Err("Embedded glTF buffers are not supported".to_string());
+            }
+            Source::Uri(uri) => {
+                std::fs::read(dir.join(uri)).map_err(|e| format!("{}: {}", uri, e))?
+            }
+        };
+        if data.len() < buffer.length() {
+            return Err(format!("Buffer {} is truncated", buffer.index()));
+        }
+        buffers.push(data);
+    }
+
+    let root = (gltf.default_scene())
+        .or_else(|| gltf.scenes().next())
+        .ok_or("No scene in file")?;
+    let up = Transform::from(vector![0, 0, 0], vector![1, 1, 1], vector![180, 0, 0]);
+    let mut scene = Scene::empty();
+    for node in root.nodes() {
+        add_node(&mut scene, &buffers, node, &up)?;
+    }
+    scene.find_lights();
+    Ok(scene)
+}
+
+// Add the triangles of `node` and its children, given the world transform of its parent.
+// Non-uniform scale on a node with rotated children is approximated, see `Transform::combine`.
+fn add_node(
+    scene: &mut Scene,
+    buffers: &[Vec<u8>],
+    node: Node,
+    parent: &Transform,
+) -> Result<(), String> {
+    let (pos, rotation, scale) = node.transform().decomposed();
+    let mut local = Transform::from(
+        vector![pos[0], pos[1], pos[2]],
+        vector![scale[0], scale[1], scale[2]],
+        vector![0, 0, 0],
+    );
+    local.set_rotation(Quaternion {
+        w: rotation[3] as Float,
+        xyz: vector![rotation[0], rotation[1], rotation[2]],
+    });
+    let transform = parent.combine(&local);
+
+    for primitive in node.mesh().iter().flat_map(|mesh| mesh.primitives()) {
+        if primitive.mode() != Mode::Triangles {
+            continue;
+        }
+        let prop = material_prop(&primitive.material());
+        let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+        let positions: Vec<Vector<3>> = (reader.read_positions())
+            .ok_or("Mesh without vertex positions")?
+            .map(|p| vector![p[0], p[1], p[2]])
+            .collect();
+        let normals: Option<Vec<Vector<3>>> = (reader.read_normals())
+            .map(|normals| normals.map(|n| vector![n[0], n[1], n[2]]).collect());
+        if normals.as_ref().is_some_and(|n| n.len() != positions.len()) {
+            return Err("Mesh has a different number of normals than vertices".to_string());
+        }
+        let indices: Vec<usize> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().map(|i| i as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+        for corners in indices.chunks_exact(3) {
+            let &[a, b, c] = corners else { unreachable!() };
+            if a.max(b).max(c) >= positions.len() {
+                return Err("Mesh vertex index out of range".to_string());
+            }
+            scene.objects.push(Box::new(Triangle {
+                transform,
+                enabled: true,
+                material: None,
+                vertices: [positions[a], positions[b], positions[c]],
+                normals: normals.as_ref().map(|n| [n[a], n[b], n[c]]),
+                prop,
+            }));
+        }
+    }
+
+    for child in node.children() {
+        add_node(scene, buffers, child, &transform)?;
+    }
+    Ok(())
+}
+
+// Approximate a metallic-roughness material with the single lobe of `PhysProp`:
+// dielectrics are diffuse, while metals keep their roughness.
+fn material_prop(material: &Material) -> PhysProp {
+    let pbr = material.pbr_metallic_roughness();
+    let [r, g, b, alpha] = pbr.base_color_factor();
+    let metallic = pbr.metallic_factor() as Float;
+    let roughness = pbr.roughness_factor() as Float;
+    let opacity = match material.alpha_mode() {
+        AlphaMode::Blend => alpha as Float,
+        _ => 1.0,
+    };
+    let [er, eg, eb] = material.emissive_factor();
+    PhysProp {
+        roughness: 1.0 + (roughness - 1.0) * metallic,
+        emission: vector![er, eg, eb],
+        ..PhysProp::from_opacity(vector![r, g, b], opacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Write a glTF file with a single triangle, scaled by 2 in a child node of a node that
+    // is turned 60 degrees around Y and moved 5 along Z, and return its path.
+    fn write_triangle_gltf(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("soft-gltf-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut bin = Vec::new();
+        for value in [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+            bin.extend_from_slice(&value.to_le_bytes());
+        }
+        for index in [0u16, 1, 2, 0] {
+            bin.extend_from_slice(&index.to_le_bytes());
+        }
+        std::fs::write(dir.join(format!("{}.bin", name)), &bin).unwrap();
+        let (sin, cos) = (0.5f32, 0.75f32.sqrt());
+        let json = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "scene": 0,
+                "scenes": [{{ "nodes": [0] }}],
+                "nodes": [
+                    {{ "translation": [0, 0, 5], "rotation": [0, {sin}, 0, {cos}], "children": [1] }},
+                    {{ "scale": [2, 2, 2], "mesh": 0 }}
+                ],
+                "meshes": [{{ "primitives": [{{
+                    "attributes": {{ "POSITION": 0 }}, "indices": 1, "material": 0
+                }}] }}],
+                "materials": [{{ "pbrMetallicRoughness": {{
+                    "baseColorFactor": [1, 0, 0, 1], "metallicFactor": 1, "roughnessFactor": 0.25
+                }} }}],
+                "buffers": [{{ "uri": "{name}.bin", "byteLength": 44 }}],
+                "bufferViews": [
+                    {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+                    {{ "buffer": 0, "byteOffset": 36, "byteLength": 6 }}
+                ],
+                "accessors": [
+                    {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                       "min": [0, 0, 0], "max": [1, 1, 0] }},
+                    {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }}
+                ]
+            }}"#
+        );
+        let path = dir.join(format!("{}.gltf", name));
+        std::fs::write(&path, json).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn gltf_triangle_gets_node_transforms_and_material() {
+        let scene = load_gltf(&write_triangle_gltf("triangle")).unwrap();
+        assert_eq!(scene.objects.len(), 1);
+        // The corners at the origin, +X and +Y are scaled by 2, the one on X is turned
+        // to (1, 0, -sqrt 3), all move 5 along Z, then Y and Z flip to point down and forward.
+        let (min, max) = scene.objects[0].bounding_box();
+        assert!((min - vector![0, -2, -5]).magnitude() < 1e-4);
+        let turned = vector![1.0, 0.0, -(5.0 - (3.0 as Float).sqrt())];
+        assert!((max - turned).magnitude() < 1e-4, "{:?}", max.data());
+        let prop = scene.objects[0].prop();
+        assert!(prop.color == vector![1, 0, 0]);
+        assert!(prop.roughness == 0.25 && prop.opacity == 1.0);
+    }
+
+    #[test]
+    fn gltf_with_missing_buffer_is_an_error() {
+        let path = write_triangle_gltf("missing");
+        std::fs::remove_file(path.replace(".gltf", ".bin")).unwrap();
+        assert!(load_gltf(&path).is_err());
+        assert!(load_gltf("does-not-exist.gltf").is_err());
+    }
+}
@@ -0,0 +1,6 @@
+pub mod import;
+pub mod matrix;
+pub mod noise;
+pub mod scene;
+pub mod timeline;
+pub mod tracer;
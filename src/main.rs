@@ -1,17 +1,19 @@
-mod matrix;
-mod scene;
-mod tracer;
+use std::collections::HashMap;
 use std::process::exit;
 use std::sync::Arc;
 use std::thread::yield_now;
+use std::time::Instant;
 
-use crate::matrix::*;
-use crate::scene::*;
-use crate::tracer::*;
+use soft::matrix::*;
+use soft::scene::*;
+use soft::timeline::*;
+use soft::tracer::*;
+use soft::vector;
 
 use rand::thread_rng;
-use sdl2::event::Event;
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
 use sdl2::pixels::Color;
 
 fn main() {
@@ -22,21 +24,29 @@ fn main() {
         .window("Ray Tracer", 300, 300)
         .position_centered()
         .allow_highdpi()
+        .resizable()
         .build()
         .unwrap();
     let mut canvas = window.into_canvas().build().unwrap();
     let mut event_pump = sdl_ctx.event_pump().unwrap();
 
-    let tracer = Arc::new(Tracer::default());
-    let scene = Arc::new(Scene {
+    let tracer = Arc::new(Tracer {
+        preview_scale: 4,
+        ..Tracer::default()
+    });
+    let mut scene = Arc::new(Scene {
         objects: vec![
             Box::new(Sphere {
                 transform: Transform::from(vector![0, 0, 2], vector![1, 1, 1], vector![0, 0, 0]),
+                enabled: true,
+                material: None,
                 radius: 0.5,
                 prop: PhysProp::from_color(vector![1, 0, 0]),
             }),
             Box::new(Sphere {
                 transform: Transform::from(vector![-1, 0, 2], vector![1, 1, 1], vector![0, 0, 0]),
+                enabled: true,
+                material: None,
                 radius: 0.4,
                 prop: PhysProp {
                     color: vector![0, 1, 0],
@@ -44,11 +54,23 @@ fn main() {
                     ior: 1.0,
                     roughness: 0.0,
                     emission: vector![0, 0, 0],
+                    anisotropy: None,
+                    absorption: vector![0, 0, 0],
+                    dispersion: 0.0,
+                    emission_texture: None,
+                    emission_strength: 1.0,
+                    cutout_texture: None,
+                    cutout_threshold: 0.5,
+                    visibility: Visibility::ALL,
+                    shadow_catcher: false,
                 },
             }),
             Box::new(Plane {
                 transform: Transform::from(vector![0, 0.5, 2], vector![1, 1, 1], vector![90, 0, 0]),
+                enabled: true,
+                material: None,
                 prop: PhysProp::from_color(vector![0.5, 0.5, 0.5]),
+                back_prop: None,
             }),
             Box::new(Sphere {
                 transform: Transform::from(
@@ -56,6 +78,8 @@ fn main() {
                     vector![1, 1, 1],
                     vector![0, 0, 0],
                 ),
+                enabled: true,
+                material: None,
                 radius: 0.2,
                 prop: PhysProp::from_emission(vector![1, 1, 0], vector![1, 1, 0]),
             }),
@@ -65,6 +89,8 @@ fn main() {
                     vector![1, 1, 1],
                     vector![0, 0, 0],
                 ),
+                enabled: true,
+                material: None,
                 radius: 0.15,
                 prop: PhysProp {
                     ior: 1.5,
@@ -72,16 +98,44 @@ fn main() {
                     roughness: 1.0,
                     color: vector![1, 1, 1],
                     emission: vector![0, 0, 0],
+                    anisotropy: None,
+                    absorption: vector![0, 0, 0],
+                    dispersion: 0.0,
+                    emission_texture: None,
+                    emission_strength: 1.0,
+                    cutout_texture: None,
+                    cutout_threshold: 0.5,
+                    visibility: Visibility::ALL,
+                    shadow_catcher: false,
                 },
             }),
         ],
+        textures: Vec::new(),
         ground_color: vector![0.3, 0.15, 0.075],
         horizon_color: vector![0.7, 0.9, 1.0],
         skybox_color: vector![0, 0.7, 0.8],
         sun_color: vector![2, 2, 1.4],
         sun_direction: vector![1, -1, -1].as_unit_vector(),
         sun_radius: 0.8,
+        sky_lights: Vec::new(),
+        materials: HashMap::new(),
+        lights: Vec::new(),
+        sky_model: SkyModel::Gradient,
+        environment: None,
+        fog_density: 0.0,
+        fog_falloff: 0.0,
+        fog_color: vector![0, 0, 0],
+        accelerator: Accelerator::None,
     });
+    let setup = Arc::get_mut(&mut scene).unwrap();
+    setup.resolve_materials();
+    setup.find_lights();
+    if let Err(errors) = scene.validate() {
+        for error in errors {
+            eprintln!("Invalid scene: {}", error);
+        }
+        exit(1);
+    }
     let camera = Transform::from(vector![0, 0, 0], vector![1, 1, 1], vector![0, 0, 0]);
 
     if let Ok((_, _)) = canvas.output_size() {}
@@ -92,13 +146,31 @@ fn main() {
     );
     // let mut rng = thread_rng();
 
+    // Keyframes for scripted animation, such as sliding the red sphere across the floor with
+    // `timeline.add_keyframe(0, 2.0, Transform::from(vector![1, 0, 2], vector![1, 1, 1], ...))`.
+    let timeline = Timeline::new();
+    let start_time = Instant::now();
+    let mut animating = !timeline.is_empty();
+
     let mut paused = false;
     'rtx_loop: loop {
+        if !paused && animating {
+            // Play the timeline once, then let the final pose accumulate.
+            let time = start_time.elapsed().as_secs_f64() as Float;
+            animating = time < timeline.duration();
+            timeline.apply(Arc::get_mut(&mut scene).unwrap(), time);
+            buffer.reset();
+        }
         if !paused && buffer.get_frame() < u16::MAX {
             canvas.set_draw_color(Color::BLACK);
             canvas.clear();
-            // tracer.trace_image(&scene, &mut buffer, &camera, &mut rng);
-            tracer.trace_image_async(scene.clone(), &mut buffer, &camera, 8);
+            if buffer.get_frame() == 0 && tracer.preview_scale > 1 {
+                tracer.trace_preview(&scene, &mut canvas, &camera, &mut thread_rng());
+                canvas.present();
+            }
+            let frame = buffer.get_frame() as u32;
+            // tracer.trace_image(&scene, &mut buffer, &camera, &mut rng, frame);
+            tracer.trace_image_async(scene.clone(), &mut buffer, &camera, 0, frame);
             buffer.update(&mut canvas);
             canvas.present();
         } else {
@@ -117,8 +189,44 @@ fn main() {
                                 "Ray Tracer"
                             })
                             .unwrap();
+                    } else if let Some(index) = keycode
+                        .map(|key| key.into_i32() - Keycode::NUM_1.into_i32())
+                        .filter(|index| (0..9).contains(index))
+                    {
+                        // Number keys 1 to 9 toggle the first nine objects.
+                        let scene = Arc::get_mut(&mut scene).unwrap();
+                        if let Some(object) = scene.objects.get_mut(index as usize) {
+                            object.set_enabled(!object.enabled());
+                            buffer.reset();
+                        }
+                    }
+                }
+                Event::MouseButtonDown {
+                    mouse_btn: MouseButton::Left,
+                    x,
+                    y,
+                    ..
+                } => {
+                    // Mouse coordinates are in window units, not pixels, on high-DPI displays.
+                    let (window_w, window_h) = canvas.window().size();
+                    let x = x as Float * buffer.width() as Float / window_w as Float;
+                    let y = y as Float * buffer.height() as Float / window_h as Float;
+                    let ray = tracer.camera_ray(&camera, x, y, buffer.width(), buffer.height());
+                    match scene.raycast(ray) {
+                        Some(hit) => {
+                            println!("Picked object {} at {:?}", hit.object_id, hit.pos.data())
+                        }
+                        None => println!("Picked nothing"),
                     }
                 }
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..),
+                    ..
+                } => {
+                    // Start over at the new size, in pixels rather than window units.
+                    let (width, height) = canvas.output_size().unwrap();
+                    buffer.resize(width as u16, height as u16);
+                }
                 Event::Quit { .. } => break 'rtx_loop,
                 _ => {}
             }
@@ -1,18 +1,26 @@
-use std::f64::consts::TAU;
+use rand::{Rng, RngCore};
 
-use rand::{rngs::ThreadRng, Rng};
+// Floating-point type used for all math; single precision with the `f32` feature.
+#[cfg(not(feature = "f32"))]
+pub type Float = f64;
+#[cfg(feature = "f32")]
+pub type Float = f32;
+#[cfg(feature = "f32")]
+pub use std::f32::consts;
+#[cfg(not(feature = "f32"))]
+pub use std::f64::consts;
 
 // Floating-point matrix of fixed size.
 #[derive(Clone, Copy, PartialEq)]
 pub struct Matrix<const W: usize, const H: usize> {
-    data: [[f64; W]; H],
+    data: [[Float; W]; H],
 }
 
 // Matrices implement the Eq trait.
 impl<const W: usize, const H: usize> Eq for Matrix<W, H> {}
 
 impl<const W: usize, const H: usize> Matrix<W, H> {
-    pub fn from(data: [[f64; W]; H]) -> Matrix<W, H> {
+    pub fn from(data: [[Float; W]; H]) -> Matrix<W, H> {
         Matrix { data: data }
     }
     pub fn zero() -> Matrix<W, H> {
@@ -20,12 +28,32 @@ impl<const W: usize, const H: usize> Matrix<W, H> {
             data: [[0.0; W]; H],
         }
     }
-    pub fn get(&self, x: usize, y: usize) -> f64 {
+    pub fn get(&self, x: usize, y: usize) -> Float {
         self.data[y][x]
     }
-    pub fn set(&mut self, x: usize, y: usize, value: f64) {
+    pub fn set(&mut self, x: usize, y: usize, value: Float) {
         self.data[y][x] = value
     }
+    /// Copy all elements to a flat list in row-major order, for serialization or GPU buffers.
+    pub fn to_flat(self) -> Vec<Float> {
+        self.data.iter().flatten().copied().collect()
+    }
+    /// Create a matrix from the output of `to_flat`, which must hold exactly W×H elements.
+    pub fn from_flat(data: &[Float]) -> Matrix<W, H> {
+        assert_eq!(data.len(), W * H);
+        let mut tmp = Matrix::<W, H>::zero();
+        for (y, row) in data.chunks(W).enumerate() {
+            tmp.data[y].copy_from_slice(row);
+        }
+        tmp
+    }
+    /// Multiply `v` by this matrix, treating it as a row vector on the left; the same as
+    /// `v * self`. Element `i` of the result is the dot product of `v` with column `i`,
+    /// which is the transpose of the more common `matrix * column vector` convention.
+    /// This is the convention `Transform` builds its rotation matrices for.
+    pub fn transform_vector(&self, v: Vector<W>) -> Vector<H> {
+        v * *self
+    }
 }
 
 // Identity matrix constructor.
@@ -63,25 +91,25 @@ impl<const D: usize> std::ops::MulAssign<Matrix<D, D>> for Matrix<D, D> {
 }
 
 impl Matrix<3, 3> {
-    pub fn rotate_x(angle: f64) -> Matrix<3, 3> {
+    pub fn rotate_x(angle: Float) -> Matrix<3, 3> {
         let (sin, cos) = angle.sin_cos();
         Matrix::from([[1.0, 0.0, 0.0], [0.0, cos, -sin], [0.0, sin, cos]])
     }
-    pub fn rotate_y(angle: f64) -> Matrix<3, 3> {
+    pub fn rotate_y(angle: Float) -> Matrix<3, 3> {
         let (sin, cos) = angle.sin_cos();
         Matrix::from([[cos, 0.0, sin], [0.0, 1.0, 0.0], [-sin, 0.0, cos]])
     }
-    pub fn rotate_z(angle: f64) -> Matrix<3, 3> {
+    pub fn rotate_z(angle: Float) -> Matrix<3, 3> {
         let (sin, cos) = angle.sin_cos();
         Matrix::from([[cos, -sin, 0.0], [sin, cos, 0.0], [0.0, 0.0, 1.0]])
     }
-    pub fn rotate_xyz(x: f64, y: f64, z: f64) -> Matrix<3, 3> {
+    pub fn rotate_xyz(x: Float, y: Float, z: Float) -> Matrix<3, 3> {
         Matrix::rotate_x(x) * Matrix::rotate_x(y) * Matrix::rotate_x(z)
     }
     pub fn rotate(angles: Vector<3>) -> Matrix<3, 3> {
         Matrix::rotate_xyz(angles[0], angles[1], angles[2])
     }
-    pub fn scale_xyz(x: f64, y: f64, z: f64) -> Matrix<3, 3> {
+    pub fn scale_xyz(x: Float, y: Float, z: Float) -> Matrix<3, 3> {
         Matrix::from([[x, 0.0, 0.0], [0.0, y, 0.0], [0.0, 0.0, y]])
     }
     pub fn scale(coeffs: Vector<3>) -> Matrix<3, 3> {
@@ -92,7 +120,7 @@ impl Matrix<3, 3> {
 // Floating-point vector of fixed size.
 #[derive(Clone, Copy, PartialEq)]
 pub struct Vector<const L: usize> {
-    data: [f64; L],
+    data: [Float; L],
 }
 
 // Vectors implement the Eq trait.
@@ -100,24 +128,24 @@ impl<const L: usize> Eq for Vector<L> {}
 
 // Contructors and vector functions.
 impl<const L: usize> Vector<L> {
-    pub fn new(data: [f64; L]) -> Vector<L> {
+    pub fn new(data: [Float; L]) -> Vector<L> {
         Vector::<L> { data: data }
     }
     pub fn zero() -> Vector<L> {
         Vector::<L> { data: [0.0; L] }
     }
-    pub fn from<T: AsF64 + Copy>(data: [T; L]) -> Vector<L> {
+    pub fn from<T: AsFloat + Copy>(data: [T; L]) -> Vector<L> {
         Vector::<L> {
-            data: data.map(|i| i.to_f64()),
+            data: data.map(|i| i.to_float()),
         }
     }
-    pub fn data(&self) -> [f64; L] {
+    pub fn data(&self) -> [Float; L] {
         self.data
     }
-    pub fn sqr_magnitude(&self) -> f64 {
+    pub fn sqr_magnitude(&self) -> Float {
         self.data.iter().map(|f| f * f).sum()
     }
-    pub fn magnitude(&self) -> f64 {
+    pub fn magnitude(&self) -> Float {
         self.sqr_magnitude().sqrt()
     }
     pub fn as_unit_vector(&self) -> Vector<L> {
@@ -126,15 +154,82 @@ impl<const L: usize> Vector<L> {
     pub fn to_unit_vector(&mut self) {
         *self /= self.magnitude();
     }
-    pub fn dot(&self, other: Vector<L>) -> f64 {
+    pub fn dot(&self, other: Vector<L>) -> Float {
         let mut sum = 0.0;
         for i in 0..L {
             sum += self[i] * other[i];
         }
         sum
     }
+    pub fn distance_squared(&self, other: Vector<L>) -> Float {
+        (*self - other).sqr_magnitude()
+    }
+    pub fn distance(&self, other: Vector<L>) -> Float {
+        (*self - other).magnitude()
+    }
+    pub fn max_component(&self) -> Float {
+        self.data
+            .iter()
+            .copied()
+            .fold(Float::NEG_INFINITY, Float::max)
+    }
+    pub fn min_component(&self) -> Float {
+        self.data.iter().copied().fold(Float::INFINITY, Float::min)
+    }
+    /// Product of each pair of components, the same as `*`.
+    pub fn hadamard(&self, other: Vector<L>) -> Vector<L> {
+        *self * other
+    }
+    /// Smaller of each pair of components.
+    pub fn min(&self, other: Vector<L>) -> Vector<L> {
+        let mut tmp = *self;
+        for i in 0..L {
+            tmp[i] = tmp[i].min(other[i]);
+        }
+        tmp
+    }
+    /// Larger of each pair of components.
+    pub fn max(&self, other: Vector<L>) -> Vector<L> {
+        let mut tmp = *self;
+        for i in 0..L {
+            tmp[i] = tmp[i].max(other[i]);
+        }
+        tmp
+    }
+    /// Clamp every component to the range 0 to 1.
+    pub fn saturate(&self) -> Vector<L> {
+        Vector {
+            data: self.data.map(|f| f.clamp(0.0, 1.0)),
+        }
+    }
+    /// Raise every component to the same power.
+    pub fn powf(&self, exp: Float) -> Vector<L> {
+        Vector {
+            data: self.data.map(|f| f.powf(exp)),
+        }
+    }
+    /// Raise every component to the power in the matching component of `exps`.
+    pub fn powv(&self, exps: Vector<L>) -> Vector<L> {
+        let mut tmp = *self;
+        for i in 0..L {
+            tmp[i] = tmp[i].powf(exps[i]);
+        }
+        tmp
+    }
+    /// Add a scalar to every component.
+    pub fn add_scalar<T: AsFloat + Copy>(&self, rhs: T) -> Vector<L> {
+        Vector {
+            data: self.data.map(|f| f + rhs.to_float()),
+        }
+    }
+    /// Subtract a scalar from every component.
+    pub fn sub_scalar<T: AsFloat + Copy>(&self, rhs: T) -> Vector<L> {
+        Vector {
+            data: self.data.map(|f| f - rhs.to_float()),
+        }
+    }
     /// Random unit vector.
-    pub fn random_unit_vector(rng: &mut ThreadRng) -> Vector<L> {
+    pub fn random_unit_vector(rng: &mut dyn RngCore) -> Vector<L> {
         let mut tmp = [0.0; L];
         for i in 0..L {
             tmp[i] = random_normal(rng);
@@ -142,7 +237,7 @@ impl<const L: usize> Vector<L> {
         Vector::from(tmp).as_unit_vector()
     }
     /// Random unit vector in a hemisphere.
-    pub fn random_hemisphere_vector(rng: &mut ThreadRng, relative_to: Vector<L>) -> Vector<L> {
+    pub fn random_hemisphere_vector(rng: &mut dyn RngCore, relative_to: Vector<L>) -> Vector<L> {
         let tmp = Vector::random_unit_vector(rng);
         if tmp.dot(relative_to) < 0.0 {
             -tmp
@@ -152,21 +247,65 @@ impl<const L: usize> Vector<L> {
     }
 }
 
+impl Vector<3> {
+    pub fn cross(&self, other: Vector<3>) -> Vector<3> {
+        Vector::new([
+            self[1] * other[2] - self[2] * other[1],
+            self[2] * other[0] - self[0] * other[2],
+            self[0] * other[1] - self[1] * other[0],
+        ])
+    }
+    /// Tangent and bitangent that form an orthonormal basis with this unit normal.
+    /// The tangent is as close to `hint` as possible.
+    pub fn tangent_basis(&self, hint: Vector<3>) -> (Vector<3>, Vector<3>) {
+        let mut tangent = hint - *self * self.dot(hint);
+        if tangent.sqr_magnitude() < 0.00000001 {
+            // Hint is parallel to the normal; pick the axis least aligned with it.
+            let axis = if self[0].abs() < 0.9 {
+                Vector::new([1.0, 0.0, 0.0])
+            } else {
+                Vector::new([0.0, 1.0, 0.0])
+            };
+            tangent = axis - *self * self.dot(axis);
+        }
+        let tangent = tangent.as_unit_vector();
+        (tangent, self.cross(tangent))
+    }
+    /// Rotate around a unit `axis` by `angle` in radians, counterclockwise looking down the axis.
+    pub fn rotate_around(&self, axis: Vector<3>, angle: Float) -> Vector<3> {
+        let (sin, cos) = angle.sin_cos();
+        *self * cos + axis.cross(*self) * sin + axis * (axis.dot(*self) * (1.0 - cos))
+    }
+    /// Append `w` as a fourth component, such as 1 for a point or 0 for a direction
+    /// in homogeneous coordinates.
+    pub fn extend(&self, w: Float) -> Vector<4> {
+        Vector::new([self[0], self[1], self[2], w])
+    }
+}
+
+impl Vector<4> {
+    /// Drop the fourth component; the inverse of `Vector::<3>::extend`.
+    pub fn truncate(&self) -> Vector<3> {
+        Vector::new([self[0], self[1], self[2]])
+    }
+}
+
 // Indexing vectors.
 impl<const L: usize> std::ops::Index<usize> for Vector<L> {
-    type Output = f64;
-    fn index<'a>(&'a self, i: usize) -> &'a f64 {
+    type Output = Float;
+    fn index<'a>(&'a self, i: usize) -> &'a Float {
         &self.data[i]
     }
 }
 
 impl<const L: usize> std::ops::IndexMut<usize> for Vector<L> {
-    fn index_mut<'a>(&'a mut self, i: usize) -> &'a mut f64 {
+    fn index_mut<'a>(&'a mut self, i: usize) -> &'a mut Float {
         &mut self.data[i]
     }
 }
 
 // Vector-vector operators.
+// These are all component-wise, so `*` is the Hadamard product and not a dot or cross product.
 macro_rules! vv_op {
     ($trait:ident, $func:ident, $trait_assign:ident, $func_assign:ident, $op:tt) => {
         // Vector-vector infix.
@@ -195,19 +334,19 @@ vv_op!(Div, div, DivAssign, div_assign, /);
 macro_rules! vf_op {
     ($trait:ident, $func:ident, $trait_assign:ident, $func_assign:ident, $op:tt) => {
         // Vector-float infix.
-        impl<const L: usize, T: AsF64+Copy> std::ops::$trait<T> for Vector<L> {
+        impl<const L: usize, T: AsFloat+Copy> std::ops::$trait<T> for Vector<L> {
             type Output = Vector<L>;
             fn $func(self, rhs: T) -> Vector<L> {
-                let rhs_tmp = rhs.to_f64();
+                let rhs_tmp = rhs.to_float();
                 let mut tmp = Vector::<L>::zero();
                 for i in 0..L { tmp[i] = self[i] $op rhs_tmp; }
                 tmp
             }
         }
         // Vector-float assign.
-        impl<const L: usize, T: AsF64+Copy> std::ops::$trait_assign<T> for Vector<L> {
+        impl<const L: usize, T: AsFloat+Copy> std::ops::$trait_assign<T> for Vector<L> {
             fn $func_assign(&mut self, rhs: T) {
-                let rhs_tmp = rhs.to_f64();
+                let rhs_tmp = rhs.to_float();
                 for i in 0..L { self[i] = self[i] $op rhs_tmp; }
             }
         }
@@ -216,6 +355,21 @@ macro_rules! vf_op {
 vf_op!(Mul, mul, MulAssign, mul_assign, *);
 vf_op!(Div, div, DivAssign, div_assign, /);
 
+// Float-vector multiplication.
+macro_rules! fv_mul {
+    ($($type:ty),+) => {
+        $(
+            impl<const L: usize> std::ops::Mul<Vector<L>> for $type {
+                type Output = Vector<L>;
+                fn mul(self, rhs: Vector<L>) -> Vector<L> {
+                    rhs * self
+                }
+            }
+        )+
+    };
+}
+fv_mul!(f32, f64, isize, usize, i128, u128, i64, u64, i32, u32, i16, u16, i8, u8);
+
 // Vector-matrix multiplication.
 impl<const W: usize, const H: usize> std::ops::Mul<Matrix<W, H>> for Vector<W> {
     type Output = Vector<H>;
@@ -239,96 +393,327 @@ impl<const L: usize> std::ops::Neg for Vector<L> {
     type Output = Vector<L>;
     fn neg(self) -> Vector<L> {
         Vector {
-            data: self.data.map(f64::neg),
+            data: self.data.map(Float::neg),
         }
     }
 }
 
-// Helpers for converting into f64.
-pub trait AsF64 {
-    fn to_f64(self) -> f64;
+/// A rotation as a unit quaternion, with real part `w` and imaginary part `xyz`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Quaternion {
+    pub w: Float,
+    pub xyz: Vector<3>,
 }
-impl AsF64 for f32 {
-    fn to_f64(self) -> f64 {
-        self as f64
+
+impl Quaternion {
+    pub fn identity() -> Quaternion {
+        Quaternion {
+            w: 1.0,
+            xyz: Vector::zero(),
+        }
+    }
+    /// Get the rotation that maps the X, Y and Z axes onto the orthonormal `x`, `y` and `z`.
+    pub fn from_axes(x: Vector<3>, y: Vector<3>, z: Vector<3>) -> Quaternion {
+        // Pick the largest of the four components to divide by, for numerical stability.
+        let trace = x[0] + y[1] + z[2];
+        let (w, xyz) = if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            (
+                0.25 * s,
+                [(y[2] - z[1]) / s, (z[0] - x[2]) / s, (x[1] - y[0]) / s],
+            )
+        } else if x[0] > y[1] && x[0] > z[2] {
+            let s = (1.0 + x[0] - y[1] - z[2]).sqrt() * 2.0;
+            (
+                (y[2] - z[1]) / s,
+                [0.25 * s, (y[0] + x[1]) / s, (z[0] + x[2]) / s],
+            )
+        } else if y[1] > z[2] {
+            let s = (1.0 + y[1] - x[0] - z[2]).sqrt() * 2.0;
+            (
+                (z[0] - x[2]) / s,
+                [(y[0] + x[1]) / s, 0.25 * s, (z[1] + y[2]) / s],
+            )
+        } else {
+            let s = (1.0 + z[2] - x[0] - y[1]).sqrt() * 2.0;
+            (
+                (x[1] - y[0]) / s,
+                [(z[0] + x[2]) / s, (z[1] + y[2]) / s, 0.25 * s],
+            )
+        };
+        Quaternion {
+            w,
+            xyz: Vector::new(xyz),
+        }
+        .normalized()
+    }
+    pub fn normalized(&self) -> Quaternion {
+        let magnitude = (self.w * self.w + self.xyz.sqr_magnitude()).sqrt();
+        Quaternion {
+            w: self.w / magnitude,
+            xyz: self.xyz / magnitude,
+        }
+    }
+    /// Apply the rotation to a vector.
+    pub fn rotate(&self, v: Vector<3>) -> Vector<3> {
+        let t = self.xyz.cross(v) * 2.0;
+        v + t * self.w + self.xyz.cross(t)
+    }
+    /// Spherical linear interpolation, which rotates at a constant rate along the shortest arc
+    /// from this rotation at `t` of 0 to `other` at `t` of 1.
+    pub fn slerp(&self, other: Quaternion, t: Float) -> Quaternion {
+        let mut other = other;
+        let mut dot = self.w * other.w + self.xyz.dot(other.xyz);
+        // `q` and `-q` are the same rotation; flip to the one on the near side.
+        if dot < 0.0 {
+            other = Quaternion {
+                w: -other.w,
+                xyz: -other.xyz,
+            };
+            dot = -dot;
+        }
+        let (a, b) = if dot > 0.9995 {
+            // Nearly identical; fall back to linear to avoid dividing by a tiny sine.
+            (1.0 - t, t)
+        } else {
+            let theta = dot.acos();
+            let sin = theta.sin();
+            (((1.0 - t) * theta).sin() / sin, (t * theta).sin() / sin)
+        };
+        Quaternion {
+            w: self.w * a + other.w * b,
+            xyz: self.xyz * a + other.xyz * b,
+        }
+        .normalized()
     }
 }
-impl AsF64 for f64 {
-    fn to_f64(self) -> f64 {
-        self
+
+// Helpers for converting into Float.
+pub trait AsFloat {
+    fn to_float(self) -> Float;
+}
+impl AsFloat for f32 {
+    fn to_float(self) -> Float {
+        self as Float
+    }
+}
+impl AsFloat for f64 {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
-impl AsF64 for isize {
-    fn to_f64(self) -> f64 {
-        self as f64
+impl AsFloat for isize {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
-impl AsF64 for usize {
-    fn to_f64(self) -> f64 {
-        self as f64
+impl AsFloat for usize {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
-impl AsF64 for i128 {
-    fn to_f64(self) -> f64 {
-        self as f64
+impl AsFloat for i128 {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
-impl AsF64 for u128 {
-    fn to_f64(self) -> f64 {
-        self as f64
+impl AsFloat for u128 {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
-impl AsF64 for i64 {
-    fn to_f64(self) -> f64 {
-        self as f64
+impl AsFloat for i64 {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
-impl AsF64 for u64 {
-    fn to_f64(self) -> f64 {
-        self as f64
+impl AsFloat for u64 {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
-impl AsF64 for i32 {
-    fn to_f64(self) -> f64 {
-        self as f64
+impl AsFloat for i32 {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
-impl AsF64 for u32 {
-    fn to_f64(self) -> f64 {
-        self as f64
+impl AsFloat for u32 {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
-impl AsF64 for i16 {
-    fn to_f64(self) -> f64 {
-        self as f64
+impl AsFloat for i16 {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
-impl AsF64 for u16 {
-    fn to_f64(self) -> f64 {
-        self as f64
+impl AsFloat for u16 {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
-impl AsF64 for i8 {
-    fn to_f64(self) -> f64 {
-        self as f64
+impl AsFloat for i8 {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
-impl AsF64 for u8 {
-    fn to_f64(self) -> f64 {
-        self as f64
+impl AsFloat for u8 {
+    fn to_float(self) -> Float {
+        self as Float
     }
 }
 
 #[macro_export]
 macro_rules! vector {
     ($($x:expr),+) => {
-        Vector::from([$(AsF64::to_f64($x)),+])
+        Vector::from([$(AsFloat::to_float($x)),+])
     };
 }
 
 // Random value in normal distribution where mean=1 and sd=1.
-pub fn random_normal(rng: &mut ThreadRng) -> f64 {
-    let t = TAU * rng.gen::<f64>();
-    let r = (rng.gen::<f64>().ln() * -2.0).sqrt();
+pub fn random_normal(rng: &mut dyn RngCore) -> Float {
+    let t = consts::TAU * rng.gen::<Float>();
+    let r = (rng.gen::<Float>().ln() * -2.0).sqrt();
     r * t.cos()
 }
+
+// Uniformly distributed point in the unit disk, by Shirley's concentric mapping of the square,
+// which keeps neighbouring random numbers close together on the disk.
+pub fn sample_disk(rng: &mut dyn RngCore) -> Vector<2> {
+    let a = rng.gen::<Float>() * 2.0 - 1.0;
+    let b = rng.gen::<Float>() * 2.0 - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vector::zero();
+    }
+    let (radius, angle) = if a.abs() > b.abs() {
+        (a, consts::FRAC_PI_4 * (b / a))
+    } else {
+        (b, consts::FRAC_PI_2 - consts::FRAC_PI_4 * (a / b))
+    };
+    Vector::new([radius * angle.cos(), radius * angle.sin()])
+}
+
+/// Map a unit direction to equirectangular UV coordinates from 0 to 1.
+/// U wraps around the Y axis with the seam towards -Z, and V goes from 0 straight up (-Y) to 1 down.
+/// Both poles map to U of 0.5 so they don't depend on rounding of X and Z.
+pub fn direction_to_equirect_uv(dir: Vector<3>) -> Vector<2> {
+    let v = (-dir[1]).clamp(-1.0, 1.0).acos() / consts::PI;
+    if dir[0] == 0.0 && dir[2] == 0.0 {
+        return Vector::new([0.5, v]);
+    }
+    let u = (dir[0].atan2(dir[2]) / consts::TAU + 0.5).rem_euclid(1.0);
+    Vector::new([u, v])
+}
+
+/// Map equirectangular UV coordinates back to a unit direction;
+/// the inverse of `direction_to_equirect_uv`.
+pub fn equirect_uv_to_direction(uv: Vector<2>) -> Vector<3> {
+    let (sin_theta, cos_theta) = (uv[1] * consts::PI).sin_cos();
+    let (sin_phi, cos_phi) = ((uv[0] - 0.5) * consts::TAU).sin_cos();
+    Vector::new([sin_theta * sin_phi, -cos_theta, sin_theta * cos_phi])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn distance_matches_magnitude() {
+        let a = vector![1, 2, 3];
+        let b = vector![4, 6, 3];
+        assert_eq!(a.distance(b), 5.0);
+        assert_eq!(a.distance_squared(b), 25.0);
+        assert_eq!(a.distance(b), (a - b).magnitude());
+    }
+
+    #[test]
+    fn scalar_times_vector() {
+        assert!(2.0 * vector![1, 2, 3] == vector![2, 4, 6]);
+        assert!(3 * vector![1, 2, 3] == vector![1, 2, 3] * 3.0);
+    }
+
+    #[test]
+    fn scalar_add_and_sub_broadcast() {
+        assert!(vector![1, 2, 3].add_scalar(0.5) == vector![1.5, 2.5, 3.5]);
+        assert!(vector![1, 2, 3].sub_scalar(1) == vector![0, 1, 2]);
+    }
+
+    #[test]
+    fn powers_match_float_powf() {
+        let v = vector![1.5, 2, 4];
+        let p = v.powf(2.2);
+        let q = v.powv(vector![2, 0.5, 3]);
+        for i in 0..3 {
+            assert_eq!(p[i], v[i].powf(2.2));
+        }
+        assert_eq!(q[0], 2.25);
+        assert_eq!(q[1], (2.0 as Float).sqrt());
+        assert_eq!(q[2], 64.0);
+    }
+
+    #[test]
+    fn vector_product_is_component_wise() {
+        let (a, b) = (vector![1, 2, 3], vector![4, 5, 6]);
+        assert!(a.hadamard(b) == vector![4, 10, 18]);
+        assert!(a * b == a.hadamard(b));
+    }
+
+    #[test]
+    fn flat_round_trip() {
+        let m3 = Matrix::from([[1.0, 2.0, 3.0], [4.0, 5.0, 6.0], [7.0, 8.0, 9.0]]);
+        let flat = m3.to_flat();
+        assert_eq!(flat, (1..=9).map(|i| i as Float).collect::<Vec<_>>());
+        assert!(Matrix::<3, 3>::from_flat(&flat) == m3);
+
+        let mut m4 = Matrix::<4, 4>::zero();
+        for i in 0..16 {
+            m4.set(i % 4, i / 4, i as Float * 0.5);
+        }
+        assert!(Matrix::<4, 4>::from_flat(&m4.to_flat()) == m4);
+    }
+
+    #[test]
+    fn disk_samples_are_uniform_in_the_unit_disk() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let count = 20000;
+        let mut inner = 0;
+        let mut right = 0;
+        for _ in 0..count {
+            let p = sample_disk(&mut rng);
+            assert!(p.sqr_magnitude() <= 1.0 + 0.000001);
+            inner += (p.sqr_magnitude() < 0.25) as u32;
+            right += (p[0] > 0.0) as u32;
+        }
+        // A quarter of the area lies within half the radius.
+        let inner = inner as Float / count as Float;
+        let right = right as Float / count as Float;
+        assert!((inner - 0.25).abs() < 0.02, "inner fraction {}", inner);
+        assert!((right - 0.5).abs() < 0.02, "right fraction {}", right);
+    }
+
+    #[test]
+    fn transform_vector_matches_operator() {
+        let m = Matrix::rotate_xyz(0.3, -0.7, 1.1) * Matrix::scale_xyz(2.0, 0.5, 1.5);
+        let v = vector![0.2, -1, 3];
+        assert!(m.transform_vector(v) == v * m);
+        // Row-vector convention: the result is `v` dotted with each column.
+        let first_column = vector![m.get(0, 0), m.get(0, 1), m.get(0, 2)];
+        assert_eq!(m.transform_vector(v)[0], v.dot(first_column));
+    }
+
+    #[test]
+    fn extend_and_truncate_round_trip() {
+        let v = vector![1, -2, 3];
+        let h = v.extend(1.0);
+        assert!(h == vector![1, -2, 3, 1]);
+        assert!(h.truncate() == v);
+        assert!(v.extend(0.0).truncate() == v);
+    }
+
+    #[test]
+    fn saturate_clamps_to_unit_range() {
+        let v = vector![2.0, -0.5, 0.25];
+        assert!(v.saturate() == vector![1.0, 0.0, 0.25]);
+    }
+}
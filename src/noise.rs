@@ -0,0 +1,137 @@
+use crate::matrix::*;
+
+// Gradient directions: the midpoints of the edges of a cube.
+const GRADIENTS: [[Float; 3]; 12] = [
+    [1.0, 1.0, 0.0],
+    [-1.0, 1.0, 0.0],
+    [1.0, -1.0, 0.0],
+    [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0],
+    [0.0, -1.0, 1.0],
+    [0.0, 1.0, -1.0],
+    [0.0, -1.0, -1.0],
+];
+
+// Hash a lattice point into a gradient index.
+fn hash(x: i64, y: i64, z: i64, seed: u32) -> usize {
+    let mut h = seed as u64 ^ 0x9e3779b97f4a7c15;
+    for i in [x, y, z] {
+        h ^= i as u64;
+        h = h.wrapping_mul(0xbf58476d1ce4e5b9);
+        h ^= h >> 31;
+    }
+    (h % 12) as usize
+}
+
+// Quintic smoothstep, which keeps the noise continuous up to the second derivative.
+fn fade(t: Float) -> Float {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: Float, b: Float, t: Float) -> Float {
+    a + (b - a) * t
+}
+
+/// Gradient noise at `p`, in roughly the range -1 to 1. Shorthand for `perlin3_seeded(p, 0)`.
+pub fn perlin3(p: Vector<3>) -> Float {
+    perlin3_seeded(p, 0)
+}
+
+/// Gradient noise at `p`, in roughly the range -1 to 1.
+/// The same `seed` always produces the same noise field, and different seeds give
+/// unrelated fields, so several materials can use noise without sharing a pattern.
+pub fn perlin3_seeded(p: Vector<3>, seed: u32) -> Float {
+    let cell = p.data().map(Float::floor);
+    let frac = p - Vector::new(cell);
+    let cell = cell.map(|f| f as i64);
+
+    let corner = |dx: i64, dy: i64, dz: i64| {
+        let grad = GRADIENTS[hash(cell[0] + dx, cell[1] + dy, cell[2] + dz, seed)];
+        Vector::new(grad).dot(frac - Vector::from([dx, dy, dz]))
+    };
+
+    let u = fade(frac[0]);
+    let v = fade(frac[1]);
+    let w = fade(frac[2]);
+    lerp(
+        lerp(
+            lerp(corner(0, 0, 0), corner(1, 0, 0), u),
+            lerp(corner(0, 1, 0), corner(1, 1, 0), u),
+            v,
+        ),
+        lerp(
+            lerp(corner(0, 0, 1), corner(1, 0, 1), u),
+            lerp(corner(0, 1, 1), corner(1, 1, 1), u),
+            v,
+        ),
+        w,
+    )
+}
+
+/// Fractal Brownian motion: `octaves` layers of `perlin3_seeded`, each at double the frequency
+/// and half the amplitude of the last. Normalized to the same range as `perlin3`.
+pub fn fbm3(p: Vector<3>, seed: u32, octaves: u32) -> Float {
+    let mut sum = 0.0;
+    let mut total = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    for i in 0..octaves {
+        sum += perlin3_seeded(p * frequency, seed.wrapping_add(i)) * amplitude;
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    if total > 0.0 {
+        sum / total
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn perlin3_is_bounded_and_deterministic() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10000 {
+            let p = vector![
+                rng.gen_range(-50.0..50.0),
+                rng.gen_range(-50.0..50.0),
+                rng.gen_range(-50.0..50.0)
+            ];
+            let value = perlin3(p);
+            assert!((-1.0..=1.0).contains(&value), "perlin3 = {}", value);
+            assert_eq!(value, perlin3(p));
+            assert!((-1.0..=1.0).contains(&fbm3(p, 7, 5)));
+        }
+    }
+
+    #[test]
+    fn perlin3_is_continuous_across_cell_boundaries() {
+        let eps = 0.0001;
+        for axis in 0..3 {
+            for boundary in [-2.0, 0.0, 1.0, 3.0] {
+                let mut below = vector![0.3, 0.6, 0.45];
+                let mut above = below;
+                below[axis] = boundary - eps;
+                above[axis] = boundary + eps;
+                let step = (perlin3_seeded(below, 3) - perlin3_seeded(above, 3)).abs();
+                assert!(
+                    step < 0.001,
+                    "jump of {} at {} on axis {}",
+                    step,
+                    boundary,
+                    axis
+                );
+            }
+        }
+    }
+}
@@ -1,8 +1,28 @@
+use std::collections::HashMap;
+
+use rand::{Rng, RngCore};
 use sdl2::pixels::Color;
 
+use crate::matrix::consts::PI;
 use crate::matrix::*;
 use crate::*;
 
+/// Minimum distance along a ray for a hit to count. Rays leaving a surface would otherwise
+/// hit that same surface again due to rounding, which shows up as speckled shadow acne.
+pub const RAY_EPSILON: Float = 0.00000001;
+/// Directions and determinants below this are treated as zero: the ray grazes or runs parallel
+/// to the surface, and dividing by them would blow up.
+pub const PARALLEL_EPSILON: Float = 0.00000001;
+/// Smallest magnitude of a scale component, because `world_to_local` divides by the scale.
+pub const MIN_SCALE: Float = 0.00000001;
+// Most cutout-masked hits skipped on a single object by one ray.
+const MAX_CUTOUT_LAYERS: usize = 64;
+// Rays starting within this fraction of a sphere's squared radius from its surface start on it.
+const SURFACE_EPSILON: Float = 0.000001;
+/// Hits whose distances differ by less than this fraction of the distance are on coincident
+/// surfaces as far as raycasts are concerned, and go to the object listed first.
+pub const COINCIDENT_EPSILON: Float = 0.000001;
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Transform {
     pos: Vector<3>,
@@ -25,7 +45,7 @@ impl Transform {
     pub fn from(pos: Vector<3>, scale: Vector<3>, angle: Vector<3>) -> Transform {
         let mut tmp = Transform {
             pos: pos,
-            scale: scale,
+            scale: Transform::guard_scale(scale),
             angle: angle,
             mtx: Matrix::zero(),
             inv_mtx: Matrix::zero(),
@@ -45,10 +65,22 @@ impl Transform {
         &self.scale
     }
     pub fn set_scale(&mut self, scale: Vector<3>) {
-        self.scale = scale;
+        self.scale = Transform::guard_scale(scale);
         self.gen_mtx();
     }
 
+    /// Clamp near-zero scale components to `MIN_SCALE`, keeping their sign,
+    /// because `world_to_local` divides by the scale.
+    /// `Scene::validate` reports the clamped components.
+    fn guard_scale(mut scale: Vector<3>) -> Vector<3> {
+        for i in 0..3 {
+            if scale[i].abs() < MIN_SCALE {
+                scale[i] = MIN_SCALE.copysign(scale[i]);
+            }
+        }
+        scale
+    }
+
     pub fn angle<'a>(&'a self) -> &'a Vector<3> {
         &self.angle
     }
@@ -57,6 +89,145 @@ impl Transform {
         self.gen_mtx();
     }
 
+    /// Rotate so that the local Z axis faces `target`, keeping the horizon level.
+    pub fn look_at(&mut self, target: Vector<3>) {
+        let dir = (target - self.pos).as_unit_vector();
+        let yaw = dir[0].atan2(dir[2]);
+        let pitch = (-dir[1]).clamp(-1.0, 1.0).asin();
+        self.set_yaw_pitch(yaw, pitch);
+    }
+
+    /// Orbit around `target` by the given yaw and pitch in degrees, while facing it.
+    /// Pitch is clamped just short of the poles to prevent flipping over.
+    pub fn orbit(&mut self, target: Vector<3>, delta_yaw: Float, delta_pitch: Float) {
+        let offset = self.pos - target;
+        let radius = offset.magnitude();
+        let dir = -offset / radius;
+        let yaw = dir[0].atan2(dir[2]) + delta_yaw.to_radians();
+        let pitch = ((-dir[1]).clamp(-1.0, 1.0).asin() + delta_pitch.to_radians())
+            .clamp(-(89.0 as Float).to_radians(), (89.0 as Float).to_radians());
+        let (yaw_sin, yaw_cos) = yaw.sin_cos();
+        let (pitch_sin, pitch_cos) = pitch.sin_cos();
+        let dir = vector![yaw_sin * pitch_cos, -pitch_sin, yaw_cos * pitch_cos];
+        self.pos = target - dir * radius;
+        self.set_yaw_pitch(yaw, pitch);
+    }
+
+    /// Move along the local Z axis by `distance`.
+    pub fn dolly(&mut self, distance: Float) {
+        self.pos += self.forward() * distance;
+    }
+
+    /// World-space direction of the local X axis, which is right for a camera.
+    pub fn right(&self) -> Vector<3> {
+        self.normal_local_to_world(vector![1, 0, 0])
+    }
+    /// World-space direction of the local -Y axis, which is up for a camera since +Y is down.
+    pub fn up(&self) -> Vector<3> {
+        self.normal_local_to_world(vector![0, -1, 0])
+    }
+    /// World-space direction of the local Z axis, which a camera looks along.
+    pub fn forward(&self) -> Vector<3> {
+        self.normal_local_to_world(vector![0, 0, 1])
+    }
+
+    /// Get the transform that undoes this one, so that
+    /// `t.inverse().local_to_world(t.local_to_world(p))` is `p`.
+    /// Only exact for uniform scale, as non-uniform scale after a rotation
+    /// can't be expressed as a `Transform`.
+    pub fn inverse(&self) -> Transform {
+        let angle = Transform::angle_from_axes(
+            self.normal_world_to_local(vector![1, 0, 0]),
+            self.normal_world_to_local(vector![0, 1, 0]),
+            self.normal_world_to_local(vector![0, 0, 1]),
+        );
+        let scale = vector![
+            1.0 / self.scale[0],
+            1.0 / self.scale[1],
+            1.0 / self.scale[2]
+        ];
+        Transform::from(self.world_to_local(vector![0, 0, 0]), scale, angle)
+    }
+
+    /// Get the world transform of `child`, given that this is the transform of its parent.
+    /// Only exact if this transform has uniform scale, as non-uniform scale before a rotation
+    /// can't be expressed as a `Transform`; the scales are multiplied per axis instead.
+    pub fn combine(&self, child: &Transform) -> Transform {
+        let angle = Transform::angle_from_axes(
+            self.normal_local_to_world(child.normal_local_to_world(vector![1, 0, 0])),
+            self.normal_local_to_world(child.normal_local_to_world(vector![0, 1, 0])),
+            self.normal_local_to_world(child.normal_local_to_world(vector![0, 0, 1])),
+        );
+        Transform::from(
+            self.local_to_world(child.pos),
+            self.scale * child.scale,
+            angle,
+        )
+    }
+
+    /// Rotate by `angle` degrees around the local-space `axis`, on top of the current rotation.
+    pub fn rotate_by(&mut self, axis: Vector<3>, angle: Float) {
+        let axis = axis.as_unit_vector();
+        let angle = angle.to_radians();
+        let rotate = |v: Vector<3>| self.normal_local_to_world(v.rotate_around(axis, angle));
+        self.angle = Transform::angle_from_axes(
+            rotate(vector![1, 0, 0]),
+            rotate(vector![0, 1, 0]),
+            rotate(vector![0, 0, 1]),
+        );
+        self.gen_mtx();
+    }
+
+    /// Get the rotation as a quaternion.
+    pub fn rotation(&self) -> Quaternion {
+        Quaternion::from_axes(
+            self.normal_local_to_world(vector![1, 0, 0]),
+            self.normal_local_to_world(vector![0, 1, 0]),
+            self.normal_local_to_world(vector![0, 0, 1]),
+        )
+    }
+    pub fn set_rotation(&mut self, rotation: Quaternion) {
+        self.angle = Transform::angle_from_axes(
+            rotation.rotate(vector![1, 0, 0]),
+            rotation.rotate(vector![0, 1, 0]),
+            rotation.rotate(vector![0, 0, 1]),
+        );
+        self.gen_mtx();
+    }
+
+    /// Blend towards `other` by `t` from 0 to 1: position and scale linearly,
+    /// and rotation along the shortest arc.
+    pub fn interpolate(&self, other: &Transform, t: Float) -> Transform {
+        let mut out = Transform::from(
+            self.pos + (other.pos - self.pos) * t,
+            self.scale + (other.scale - self.scale) * t,
+            vector![0, 0, 0],
+        );
+        out.set_rotation(self.rotation().slerp(other.rotation(), t));
+        out
+    }
+
+    /// Get the Euler angles of the rotation that maps the X, Y and Z axes onto `x`, `y` and `z`.
+    fn angle_from_axes(x: Vector<3>, y: Vector<3>, z: Vector<3>) -> Vector<3> {
+        vector![
+            -(-z[1]).atan2(z[2]).to_degrees(),
+            -z[0].clamp(-1.0, 1.0).asin().to_degrees(),
+            -(-y[0]).atan2(x[0]).to_degrees()
+        ]
+    }
+
+    /// Set the rotation from a yaw around Y followed by a pitch around the local X axis, in radians.
+    fn set_yaw_pitch(&mut self, yaw: Float, pitch: Float) {
+        let (yaw_sin, yaw_cos) = yaw.sin_cos();
+        let (pitch_sin, pitch_cos) = pitch.sin_cos();
+        self.angle = vector![
+            -pitch_sin.atan2(yaw_cos * pitch_cos).to_degrees(),
+            -(yaw_sin * pitch_cos).clamp(-1.0, 1.0).asin().to_degrees(),
+            -(-yaw_sin * pitch_sin).atan2(yaw_cos).to_degrees()
+        ];
+        self.gen_mtx();
+    }
+
     fn gen_mtx(&mut self) {
         self.mtx = Matrix::rotate_x(self.angle[0].to_radians())
             * Matrix::rotate_y(self.angle[1].to_radians())
@@ -85,6 +256,28 @@ impl Transform {
     pub fn normal_local_to_world(&self, normal: Vector<3>) -> Vector<3> {
         normal * self.mtx
     }
+    /// Transform a surface normal to world space as a unit vector.
+    /// Unlike directions, normals are divided by the scale, so they stay perpendicular
+    /// to the surface under non-uniform scale. Objects should use this for `Intersect::normal`.
+    pub fn surface_normal_local_to_world(&self, normal: Vector<3>) -> Vector<3> {
+        (normal / self.scale * self.mtx).as_unit_vector()
+    }
+
+    /// Get the world-space bounding box, as minimum and maximum corners, of a local-space box.
+    pub fn box_local_to_world(&self, min: Vector<3>, max: Vector<3>) -> (Vector<3>, Vector<3>) {
+        let mut out_min = vector![Float::INFINITY, Float::INFINITY, Float::INFINITY];
+        let mut out_max = -out_min;
+        for corner in 0..8 {
+            let pos = self.local_to_world(vector![
+                if corner & 1 == 0 { min[0] } else { max[0] },
+                if corner & 2 == 0 { min[1] } else { max[1] },
+                if corner & 4 == 0 { min[2] } else { max[2] }
+            ]);
+            out_min = out_min.min(pos);
+            out_max = out_max.max(pos);
+        }
+        (out_min, out_max)
+    }
 
     pub fn ray_world_to_local(&self, ray: Ray) -> Ray {
         Ray {
@@ -108,13 +301,78 @@ pub struct Ray {
     pub normal: Vector<3>,
 }
 
+/// Purpose of a ray, which decides the objects it can see.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RayKind {
+    /// Ray from the camera.
+    Camera,
+    /// Diffuse bounce gathering light, which is blocked by objects that cast shadows.
+    Shadow,
+    /// Glossy or mirror reflection, or refraction.
+    Reflection,
+}
+
+/// Which kinds of rays can see an object.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Visibility {
+    pub camera: bool,
+    pub shadow: bool,
+    pub reflection: bool,
+}
+
+impl Visibility {
+    /// Visible to every kind of ray.
+    pub const ALL: Visibility = Visibility {
+        camera: true,
+        shadow: true,
+        reflection: true,
+    };
+
+    pub fn is_visible(&self, kind: RayKind) -> bool {
+        match kind {
+            RayKind::Camera => self.camera,
+            RayKind::Shadow => self.shadow,
+            RayKind::Reflection => self.reflection,
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub struct PhysProp {
-    pub ior: f64,
-    pub opacity: f64,
-    pub roughness: f64,
+    pub ior: Float,
+    pub opacity: Float,
+    pub roughness: Float,
     pub color: Vector<3>,
     pub emission: Vector<3>,
+    /// Directional roughness; overrides `roughness` when present.
+    pub anisotropy: Option<Anisotropy>,
+    /// Absorption coefficient per unit of distance traveled inside the object.
+    pub absorption: Vector<3>,
+    /// Cauchy coefficient B in square micrometers; how much `ior` varies with wavelength.
+    /// Only used when the tracer renders spectrally.
+    pub dispersion: Float,
+    /// Index into `Scene::textures` of an image that replaces `emission` on surfaces with UVs.
+    pub emission_texture: Option<usize>,
+    /// Multiplier for `emission_texture`.
+    pub emission_strength: Float,
+    /// Index into `Scene::textures` of a mask for surfaces with UVs: where its red channel
+    /// is below `cutout_threshold`, rays pass straight through as if nothing was there.
+    pub cutout_texture: Option<usize>,
+    pub cutout_threshold: Float,
+    pub visibility: Visibility,
+    /// Invisible to the camera, but darkens where shadowed and records that in the alpha layer,
+    /// for compositing rendered objects onto a photo.
+    pub shadow_catcher: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Anisotropy {
+    /// Roughness along the tangent.
+    pub roughness_u: Float,
+    /// Roughness along the bitangent.
+    pub roughness_v: Float,
+    /// World-space direction the tangent is aligned to, projected onto the surface.
+    pub tangent: Vector<3>,
 }
 
 impl Eq for PhysProp {}
@@ -127,15 +385,33 @@ impl PhysProp {
             roughness: 1.0,
             color,
             emission: vector![0, 0, 0],
+            anisotropy: None,
+            absorption: vector![0, 0, 0],
+            dispersion: 0.0,
+            emission_texture: None,
+            emission_strength: 1.0,
+            cutout_texture: None,
+            cutout_threshold: 0.5,
+            visibility: Visibility::ALL,
+            shadow_catcher: false,
         }
     }
-    pub fn from_opacity(color: Vector<3>, opacity: f64) -> PhysProp {
+    pub fn from_opacity(color: Vector<3>, opacity: Float) -> PhysProp {
         PhysProp {
             ior: 1.0,
             opacity,
             roughness: 1.0,
             color,
             emission: vector![0, 0, 0],
+            anisotropy: None,
+            absorption: vector![0, 0, 0],
+            dispersion: 0.0,
+            emission_texture: None,
+            emission_strength: 1.0,
+            cutout_texture: None,
+            cutout_threshold: 0.5,
+            visibility: Visibility::ALL,
+            shadow_catcher: false,
         }
     }
     pub fn from_emission(color: Vector<3>, emission: Vector<3>) -> PhysProp {
@@ -145,6 +421,15 @@ impl PhysProp {
             roughness: 1.0,
             color,
             emission,
+            anisotropy: None,
+            absorption: vector![0, 0, 0],
+            dispersion: 0.0,
+            emission_texture: None,
+            emission_strength: 1.0,
+            cutout_texture: None,
+            cutout_threshold: 0.5,
+            visibility: Visibility::ALL,
+            shadow_catcher: false,
         }
     }
 }
@@ -157,10 +442,17 @@ pub struct Intersect {
     pub normal: Vector<3>,
     /// Physical properties at the intersection.
     pub prop: PhysProp,
-    /// Distance from the ray origin.
-    pub distance: f64,
-    /// Whether the ray started outside the objct.
+    /// Distance from the ray origin in world space.
+    pub distance: Float,
+    /// Whether the ray crosses into the object here rather than out of it.
+    /// Decided by the geometric surface, never by an interpolated `normal`.
     pub is_entry: bool,
+    /// Barycentric coordinates of the intersection, for triangle-type objects.
+    pub bary: Option<Vector<3>>,
+    /// Texture coordinates of the intersection, for objects that define them.
+    pub uv: Option<Vector<2>>,
+    /// Index of the hit object in `Scene::objects`, filled in by `Scene::raycast`.
+    pub object_id: usize,
 }
 impl Eq for Intersect {}
 
@@ -170,13 +462,62 @@ pub trait Object {
     fn set_transform(&mut self, pos: Transform) {
         *self.transform_mut() = pos;
     }
+    /// Whether the object is part of the render. Rays pass through disabled objects,
+    /// but they stay in `Scene::objects` so that indices don't shift.
+    fn enabled(&self) -> bool;
+    fn set_enabled(&mut self, enabled: bool);
+    /// Name of the entry in `Scene::materials` that replaces the object's own material when
+    /// the scene's materials are resolved.
+    fn material(&self) -> Option<&str>;
+    fn prop(&self) -> &PhysProp;
+    fn set_prop(&mut self, prop: PhysProp);
+    /// Copy the object into a new box, which is what lets `Scene` be cloned.
+    fn box_clone(&self) -> Box<dyn Object + Send + Sync>;
     /// Perform an intersection test with a ray in world space.
     fn intersect(&self, ray: &Ray) -> Option<Intersect>;
+    /// Surface area in world space.
+    fn surface_area(&self) -> Float;
+    /// Pick a point in world space on the surface, uniformly by area, for sampling the object as
+    /// a light. Objects that can't return None, and are only lit by rays bouncing into them.
+    fn sample_point(&self, _rng: &mut dyn RngCore) -> Option<Vector<3>> {
+        None
+    }
+    /// Axis-aligned bounding box in world space, as minimum and maximum corners.
+    fn bounding_box(&self) -> (Vector<3>, Vector<3>);
+    /// Whether a point in world space lies inside the object.
+    /// Open surfaces have no inside and always return false.
+    fn contains(&self, _point: Vector<3>) -> bool {
+        false
+    }
+    /// Describe any degenerate parameters of this object, apart from its transform.
+    fn validate(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// The object as a `Sphere`, if it is one.
+    fn as_sphere(&self) -> Option<&Sphere> {
+        None
+    }
 }
 
+impl Clone for Box<dyn Object + Send + Sync> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+// Uniformly distributed point in the triangle with corners `a`, `b` and `c`.
+fn sample_triangle(rng: &mut dyn RngCore, a: Vector<3>, b: Vector<3>, c: Vector<3>) -> Vector<3> {
+    let r1 = rng.gen::<Float>().sqrt();
+    let r2 = rng.gen::<Float>();
+    a * (1.0 - r1) + b * (r1 * (1.0 - r2)) + c * (r1 * r2)
+}
+
+#[derive(Clone)]
 pub struct Sphere {
     pub transform: Transform,
-    pub radius: f64,
+    pub enabled: bool,
+    pub material: Option<String>,
+    pub radius: Float,
     pub prop: PhysProp,
 }
 
@@ -187,48 +528,122 @@ impl Object for Sphere {
     fn transform_mut<'a>(&'a mut self) -> &'a mut Transform {
         &mut self.transform
     }
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+    fn material(&self) -> Option<&str> {
+        self.material.as_deref()
+    }
+    fn prop(&self) -> &PhysProp {
+        &self.prop
+    }
+    fn set_prop(&mut self, prop: PhysProp) {
+        self.prop = prop;
+    }
+    fn box_clone(&self) -> Box<dyn Object + Send + Sync> {
+        Box::new(self.clone())
+    }
 
-    fn intersect(&self, ray: &Ray) -> Option<Intersect> {
-        let ray = self.transform.ray_world_to_local(*ray);
-        let a = -ray.normal.dot(ray.pos);
-        let b = a * a - ray.pos.sqr_magnitude() + self.radius * self.radius;
-
-        if b < 0.0 {
-            return None;
-        }
-        let distance: f64;
-        if b < 0.00000001 {
-            if a > 0.00000001 {
-                distance = a;
-            } else {
-                return None;
-            }
-        } else {
-            let dist0 = a + b.sqrt();
-            let dist1 = a - b.sqrt();
-            if dist0 < dist1 && dist0 > 0.00000001 {
-                distance = dist0;
-            } else if dist1 > 0.00000001 {
-                distance = dist1;
-            } else {
-                return None;
-            }
-        };
+    fn intersect(&self, ray_world: &Ray) -> Option<Intersect> {
+        let ray = self.transform.ray_world_to_local(*ray_world);
+        let distance = sphere_distance(ray, self.radius * self.radius)?;
         let pos = ray.pos + ray.normal * distance;
+        let normal = self.transform.surface_normal_local_to_world(pos);
 
         return Some(Intersect {
             pos: self.transform.local_to_world(pos),
-            normal: self.transform.normal_local_to_world(pos / self.radius),
+            normal,
             prop: self.prop,
             distance,
-            is_entry: ray.pos.sqr_magnitude() > self.radius * self.radius,
+            is_entry: ray_world.normal.dot(normal) < 0.0,
+            bary: None,
+            uv: Some(direction_to_equirect_uv(pos / self.radius)),
+            object_id: 0,
         });
     }
+
+    fn contains(&self, point: Vector<3>) -> bool {
+        self.transform.world_to_local(point).sqr_magnitude() < self.radius * self.radius
+    }
+
+    fn as_sphere(&self) -> Option<&Sphere> {
+        Some(self)
+    }
+
+    fn surface_area(&self) -> Float {
+        // Knud Thomsen's approximation, which is exact for a uniformly scaled sphere.
+        let [a, b, c] = (self.transform.scale * self.radius)
+            .data()
+            .map(|f| f.abs().powf(1.6075));
+        4.0 * PI * ((a * b + a * c + b * c) / 3.0).powf(1.0 / 1.6075)
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<Vector<3>> {
+        // Stretching a sphere bunches up the points, and the area is only approximate.
+        let scale = self.transform.scale;
+        if scale[0] != scale[1] || scale[1] != scale[2] {
+            return None;
+        }
+        let local = Vector::<3>::random_unit_vector(rng) * self.radius;
+        Some(self.transform.local_to_world(local))
+    }
+
+    fn bounding_box(&self) -> (Vector<3>, Vector<3>) {
+        let radius = vector![self.radius, self.radius, self.radius];
+        self.transform.box_local_to_world(-radius, radius)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        if self.radius > 0.0 {
+            Vec::new()
+        } else {
+            vec![format!("sphere radius {} is not positive", self.radius)]
+        }
+    }
 }
 
+// Get the distance along `ray` to a sphere around the origin, or None if it misses.
+fn sphere_distance(ray: Ray, radius_sqr: Float) -> Option<Float> {
+    let a = -ray.normal.dot(ray.pos);
+    let b = a * a - ray.pos.sqr_magnitude() + radius_sqr;
+    if b < 0.0 {
+        return None;
+    }
+    if (ray.pos.sqr_magnitude() - radius_sqr).abs() <= radius_sqr * SURFACE_EPSILON {
+        // Starting on the surface, one root is the surface itself and rounding decides
+        // which side of zero it lands on. Only a ray going inward can hit the far side.
+        if a > 0.0 && b >= PARALLEL_EPSILON {
+            Some(a + b.sqrt())
+        } else {
+            None
+        }
+    } else if b < PARALLEL_EPSILON {
+        Some(a).filter(|a| *a > RAY_EPSILON)
+    } else {
+        let dist0 = a + b.sqrt();
+        let dist1 = a - b.sqrt();
+        if dist1 > RAY_EPSILON {
+            Some(dist1)
+        } else if dist0 > RAY_EPSILON {
+            Some(dist0)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Plane {
     pub transform: Transform,
+    pub enabled: bool,
+    pub material: Option<String>,
+    /// Material of the front face, which faces local +Z, and of the back face by default.
     pub prop: PhysProp,
+    /// Material of the back face, which faces local -Z.
+    pub back_prop: Option<PhysProp>,
 }
 
 impl Object for Plane {
@@ -238,59 +653,1936 @@ impl Object for Plane {
     fn transform_mut<'a>(&'a mut self) -> &'a mut Transform {
         &mut self.transform
     }
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+    fn material(&self) -> Option<&str> {
+        self.material.as_deref()
+    }
+    fn prop(&self) -> &PhysProp {
+        &self.prop
+    }
+    fn set_prop(&mut self, prop: PhysProp) {
+        self.prop = prop;
+    }
+    fn box_clone(&self) -> Box<dyn Object + Send + Sync> {
+        Box::new(self.clone())
+    }
 
     fn intersect(&self, ray: &Ray) -> Option<Intersect> {
         let ray = self.transform.ray_world_to_local(*ray);
-        if ray.normal[2].abs() < 0.00000001 {
+        if ray.normal[2].abs() < PARALLEL_EPSILON {
             return None;
         }
         let distance = -ray.pos[2] / ray.normal[2];
-        if distance <= 0.00000001 {
+        if distance <= RAY_EPSILON {
             return None;
         }
         let pos = ray.pos + ray.normal * distance;
         if pos[0].abs() > 1.0 || pos[1].abs() > 1.0 {
             return None;
         }
+        let uv = Vector::new([(pos[0] + 1.0) * 0.5, (pos[1] + 1.0) * 0.5]);
+        Some(Intersect {
+            pos: self.transform.local_to_world(pos),
+            normal: self.transform.surface_normal_local_to_world(vector![
+                0,
+                0,
+                ray.pos[2].signum()
+            ]),
+            prop: match self.back_prop {
+                Some(back_prop) if ray.pos[2] < 0.0 => back_prop,
+                _ => self.prop,
+            },
+            distance,
+            is_entry: true,
+            bary: None,
+            uv: Some(uv),
+            object_id: 0,
+        })
+    }
+
+    fn surface_area(&self) -> Float {
+        4.0 * (self.transform.scale[0] * self.transform.scale[1]).abs()
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<Vector<3>> {
+        let x = rng.gen::<Float>() * 2.0 - 1.0;
+        let y = rng.gen::<Float>() * 2.0 - 1.0;
+        Some(self.transform.local_to_world(vector![x, y, 0]))
+    }
+
+    fn bounding_box(&self) -> (Vector<3>, Vector<3>) {
+        self.transform
+            .box_local_to_world(vector![-1, -1, 0], vector![1, 1, 0])
+    }
+}
+
+#[derive(Clone)]
+pub struct Triangle {
+    pub transform: Transform,
+    pub enabled: bool,
+    pub material: Option<String>,
+    /// Corner positions in local space.
+    pub vertices: [Vector<3>; 3],
+    /// Optional per-vertex normals in local space, interpolated for smooth shading.
+    pub normals: Option<[Vector<3>; 3]>,
+    pub prop: PhysProp,
+}
+
+impl Object for Triangle {
+    fn transform(&self) -> &Transform {
+        &self.transform
+    }
+    fn transform_mut(&mut self) -> &mut Transform {
+        &mut self.transform
+    }
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+    fn material(&self) -> Option<&str> {
+        self.material.as_deref()
+    }
+    fn prop(&self) -> &PhysProp {
+        &self.prop
+    }
+    fn set_prop(&mut self, prop: PhysProp) {
+        self.prop = prop;
+    }
+    fn box_clone(&self) -> Box<dyn Object + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersect> {
+        let ray = self.transform.ray_world_to_local(*ray);
+        let edge1 = self.vertices[1] - self.vertices[0];
+        let edge2 = self.vertices[2] - self.vertices[0];
+        let p = ray.normal.cross(edge2);
+        let det = edge1.dot(p);
+        if det.abs() < PARALLEL_EPSILON {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let t = ray.pos - self.vertices[0];
+        let u = t.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = t.cross(edge1);
+        let v = ray.normal.dot(q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let distance = edge2.dot(q) * inv_det;
+        if distance <= RAY_EPSILON {
+            return None;
+        }
+        let pos = ray.pos + ray.normal * distance;
+        let bary = vector![1.0 - u - v, u, v];
+        // The geometric normal decides which side was hit; vertex normals only affect shading.
+        let geo_normal = edge1.cross(edge2);
+        let mut normal = match self.normals {
+            Some(normals) => (normals[0] * bary[0] + normals[1] * bary[1] + normals[2] * bary[2])
+                .as_unit_vector(),
+            None => geo_normal.as_unit_vector(),
+        };
+        if geo_normal.dot(ray.normal) > 0.0 {
+            normal = -normal;
+        }
         Some(Intersect {
             pos: self.transform.local_to_world(pos),
-            normal: self
-                .transform
-                .normal_local_to_world(vector![0, 0, ray.pos[2].signum()]),
+            normal: self.transform.surface_normal_local_to_world(normal),
             prop: self.prop,
             distance,
             is_entry: true,
+            bary: Some(bary),
+            uv: None,
+            object_id: 0,
         })
     }
+
+    fn surface_area(&self) -> Float {
+        let scale = self.transform.scale;
+        let edge1 = (self.vertices[1] - self.vertices[0]) * scale;
+        let edge2 = (self.vertices[2] - self.vertices[0]) * scale;
+        edge1.cross(edge2).magnitude() * 0.5
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<Vector<3>> {
+        let [a, b, c] = self.vertices;
+        Some(self.transform.local_to_world(sample_triangle(rng, a, b, c)))
+    }
+
+    fn bounding_box(&self) -> (Vector<3>, Vector<3>) {
+        let min = self.vertices[0].min(self.vertices[1]).min(self.vertices[2]);
+        let max = self.vertices[0].max(self.vertices[1]).max(self.vertices[2]);
+        self.transform.box_local_to_world(min, max)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let edge1 = self.vertices[1] - self.vertices[0];
+        let edge2 = self.vertices[2] - self.vertices[0];
+        if edge1.cross(edge2).sqr_magnitude() > 0.0 {
+            Vec::new()
+        } else {
+            vec!["triangle has zero area".to_string()]
+        }
+    }
 }
 
-pub struct Scene {
-    /// List of objects in the scene.
-    pub objects: Vec<Box<dyn Object + Send + Sync>>,
-    /// Ground color.
-    pub ground_color: Vector<3>,
-    /// Horizon color.
-    pub horizon_color: Vector<3>,
-    /// Skybox color.
-    pub skybox_color: Vector<3>,
-    /// Sun color.
-    pub sun_color: Vector<3>,
-    /// Unit vector pointing at the sun.
-    pub sun_direction: Vector<3>,
-    /// Dot product threshold for a ray to be pointing at the sun.
-    pub sun_radius: f64,
+/// Planar convex quadrilateral, such as a tilted rectangle.
+#[derive(Clone)]
+pub struct Quad {
+    pub transform: Transform,
+    pub enabled: bool,
+    pub material: Option<String>,
+    /// Corner positions in local space, in order around the edge.
+    pub vertices: [Vector<3>; 4],
+    pub prop: PhysProp,
 }
 
-impl Scene {
-    pub fn empty() -> Scene {
-        Scene {
-            objects: Vec::new(),
-            ground_color: vector![0, 0, 0],
-            horizon_color: vector![0, 0, 0],
-            skybox_color: vector![0, 0, 0],
-            sun_color: vector![0, 0, 0],
-            sun_direction: vector![0, 0, 0],
-            sun_radius: 1.0,
+impl Quad {
+    // Normal of the plane through the corners, scaled by twice the area.
+    fn plane_normal(&self) -> Vector<3> {
+        (self.vertices[2] - self.vertices[0]).cross(self.vertices[3] - self.vertices[1])
+    }
+}
+
+impl Object for Quad {
+    fn transform(&self) -> &Transform {
+        &self.transform
+    }
+    fn transform_mut(&mut self) -> &mut Transform {
+        &mut self.transform
+    }
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+    fn material(&self) -> Option<&str> {
+        self.material.as_deref()
+    }
+    fn prop(&self) -> &PhysProp {
+        &self.prop
+    }
+    fn set_prop(&mut self, prop: PhysProp) {
+        self.prop = prop;
+    }
+    fn box_clone(&self) -> Box<dyn Object + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersect> {
+        let ray = self.transform.ray_world_to_local(*ray);
+        let plane_normal = self.plane_normal();
+        let det = plane_normal.dot(ray.normal);
+        if det.abs() < PARALLEL_EPSILON {
+            return None;
+        }
+        let distance = plane_normal.dot(self.vertices[0] - ray.pos) / det;
+        if distance <= RAY_EPSILON {
+            return None;
+        }
+        let pos = ray.pos + ray.normal * distance;
+        // The hit is inside if it is on the same side of every edge.
+        for i in 0..4 {
+            let edge = self.vertices[(i + 1) % 4] - self.vertices[i];
+            if edge.cross(pos - self.vertices[i]).dot(plane_normal) < 0.0 {
+                return None;
+            }
+        }
+        // Planar mapping with U along the first edge and V along the last.
+        let offset = pos - self.vertices[0];
+        let edge_u = self.vertices[1] - self.vertices[0];
+        let edge_v = self.vertices[3] - self.vertices[0];
+        let uv = Vector::new([
+            offset.dot(edge_u) / edge_u.sqr_magnitude(),
+            offset.dot(edge_v) / edge_v.sqr_magnitude(),
+        ]);
+        let normal = if det > 0.0 {
+            -plane_normal
+        } else {
+            plane_normal
+        };
+        Some(Intersect {
+            pos: self.transform.local_to_world(pos),
+            normal: self.transform.surface_normal_local_to_world(normal),
+            prop: self.prop,
+            distance,
+            is_entry: true,
+            bary: None,
+            uv: Some(uv),
+            object_id: 0,
+        })
+    }
+
+    fn surface_area(&self) -> Float {
+        let scale = self.transform.scale;
+        let diagonal1 = (self.vertices[2] - self.vertices[0]) * scale;
+        let diagonal2 = (self.vertices[3] - self.vertices[1]) * scale;
+        diagonal1.cross(diagonal2).magnitude() * 0.5
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<Vector<3>> {
+        // Split along the first diagonal and pick a half by its area.
+        let [v0, v1, v2, v3] = self.vertices;
+        let area1 = (v1 - v0).cross(v2 - v0).magnitude();
+        let area2 = (v2 - v0).cross(v3 - v0).magnitude();
+        let local = if rng.gen::<Float>() * (area1 + area2) < area1 {
+            sample_triangle(rng, v0, v1, v2)
+        } else {
+            sample_triangle(rng, v0, v2, v3)
+        };
+        Some(self.transform.local_to_world(local))
+    }
+
+    fn bounding_box(&self) -> (Vector<3>, Vector<3>) {
+        let [v0, v1, v2, v3] = self.vertices;
+        let min = v0.min(v1).min(v2).min(v3);
+        let max = v0.max(v1).max(v2).max(v3);
+        self.transform.box_local_to_world(min, max)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let plane_normal = self.plane_normal();
+        if plane_normal.sqr_magnitude() == 0.0 {
+            return vec!["quad has zero area".to_string()];
+        }
+        let unit_normal = plane_normal.as_unit_vector();
+        let mut errors = Vec::new();
+        for i in 1..4 {
+            if (self.vertices[i] - self.vertices[0]).dot(unit_normal).abs() > 0.000001 {
+                errors.push(format!(
+                    "quad corner {} is not in the plane of the others",
+                    i
+                ));
+            }
+        }
+        errors
+    }
+}
+
+/// Terrain defined by a grid of heights spanning -1 to 1 on the local X and Y axes,
+/// with the height along local Z.
+#[derive(Clone)]
+pub struct HeightField {
+    pub transform: Transform,
+    pub enabled: bool,
+    pub material: Option<String>,
+    pub prop: PhysProp,
+    heights: Vec<Float>,
+    width: usize,
+    depth: usize,
+    min_height: Float,
+    max_height: Float,
+}
+
+impl HeightField {
+    /// Create a height field from `width` by `depth` samples in row-major order,
+    /// each multiplied by `height_scale`.
+    pub fn new(
+        transform: Transform,
+        width: usize,
+        depth: usize,
+        heights: Vec<Float>,
+        height_scale: Float,
+        prop: PhysProp,
+    ) -> HeightField {
+        assert!(width >= 2 && depth >= 2);
+        assert_eq!(heights.len(), width * depth);
+        let heights: Vec<Float> = heights.iter().map(|h| h * height_scale).collect();
+        let min_height = heights.iter().copied().fold(Float::INFINITY, Float::min);
+        let max_height = heights
+            .iter()
+            .copied()
+            .fold(Float::NEG_INFINITY, Float::max);
+        HeightField {
+            transform,
+            enabled: true,
+            material: None,
+            prop,
+            heights,
+            width,
+            depth,
+            min_height,
+            max_height,
+        }
+    }
+
+    /// Load the heights from a binary (P5) PGM grayscale image, where white is `height_scale`.
+    pub fn from_pgm(
+        path: &str,
+        transform: Transform,
+        height_scale: Float,
+        prop: PhysProp,
+    ) -> Result<HeightField, String> {
+        let (width, depth, heights) = read_netpbm(path, "P5", 1)?;
+        if width < 2 || depth < 2 {
+            return Err("Height field needs at least 2 by 2 samples".to_string());
+        }
+        Ok(HeightField::new(
+            transform,
+            width,
+            depth,
+            heights,
+            height_scale,
+            prop,
+        ))
+    }
+
+    /// Bilinearly interpolated height at local X and Y.
+    pub fn height(&self, x: Float, y: Float) -> Float {
+        let gx =
+            ((x + 1.0) * 0.5 * (self.width - 1) as Float).clamp(0.0, (self.width - 1) as Float);
+        let gy =
+            ((y + 1.0) * 0.5 * (self.depth - 1) as Float).clamp(0.0, (self.depth - 1) as Float);
+        let x0 = (gx as usize).min(self.width - 2);
+        let y0 = (gy as usize).min(self.depth - 2);
+        let fx = gx - x0 as Float;
+        let fy = gy - y0 as Float;
+        let at = |x: usize, y: usize| self.heights[y * self.width + x];
+        let top = at(x0, y0) + (at(x0 + 1, y0) - at(x0, y0)) * fx;
+        let bottom = at(x0, y0 + 1) + (at(x0 + 1, y0 + 1) - at(x0, y0 + 1)) * fx;
+        top + (bottom - top) * fy
+    }
+
+    // Size of one grid cell in local space.
+    fn cell_size(&self) -> Float {
+        2.0 / (self.width.max(self.depth) - 1) as Float
+    }
+}
+
+impl Object for HeightField {
+    fn transform(&self) -> &Transform {
+        &self.transform
+    }
+    fn transform_mut(&mut self) -> &mut Transform {
+        &mut self.transform
+    }
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+    fn material(&self) -> Option<&str> {
+        self.material.as_deref()
+    }
+    fn prop(&self) -> &PhysProp {
+        &self.prop
+    }
+    fn set_prop(&mut self, prop: PhysProp) {
+        self.prop = prop;
+    }
+    fn box_clone(&self) -> Box<dyn Object + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersect> {
+        let ray = self.transform.ray_world_to_local(*ray);
+
+        // Clip the ray against the bounding box of the terrain.
+        let min = vector![-1, -1, self.min_height];
+        let max = vector![1, 1, self.max_height];
+        let mut t_near = RAY_EPSILON;
+        let mut t_far = Float::INFINITY;
+        for i in 0..3 {
+            if ray.normal[i].abs() < PARALLEL_EPSILON {
+                if ray.pos[i] < min[i] || ray.pos[i] > max[i] {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (min[i] - ray.pos[i]) / ray.normal[i];
+            let t1 = (max[i] - ray.pos[i]) / ray.normal[i];
+            t_near = t_near.max(t0.min(t1));
+            t_far = t_far.min(t0.max(t1));
+        }
+        if t_near > t_far {
+            return None;
+        }
+
+        // March in half-cell steps until the ray crosses the surface, then bisect.
+        let above = |t: Float| {
+            let pos = ray.pos + ray.normal * t;
+            pos[2] > self.height(pos[0], pos[1])
+        };
+        let start_above = above(t_near);
+        let step = self.cell_size() * 0.5;
+        let mut t0 = t_near;
+        let distance = loop {
+            let t1 = (t0 + step).min(t_far);
+            if above(t1) != start_above {
+                let (mut lo, mut hi) = (t0, t1);
+                for _ in 0..32 {
+                    let mid = (lo + hi) * 0.5;
+                    if above(mid) == start_above {
+                        lo = mid;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                break hi;
+            }
+            if t1 >= t_far {
+                return None;
+            }
+            t0 = t1;
+        };
+        let pos = ray.pos + ray.normal * distance;
+
+        // Normal from the slope between neighboring heights, staying inside the field.
+        let e = self.cell_size() * 0.5;
+        let (x0, x1) = ((pos[0] - e).max(-1.0), (pos[0] + e).min(1.0));
+        let (y0, y1) = ((pos[1] - e).max(-1.0), (pos[1] + e).min(1.0));
+        let dx = (self.height(x1, pos[1]) - self.height(x0, pos[1])) / (x1 - x0);
+        let dy = (self.height(pos[0], y1) - self.height(pos[0], y0)) / (y1 - y0);
+        let mut normal = vector![-dx, -dy, 1].as_unit_vector();
+        if !start_above {
+            normal = -normal;
+        }
+
+        Some(Intersect {
+            pos: self.transform.local_to_world(pos),
+            normal: self.transform.surface_normal_local_to_world(normal),
+            prop: self.prop,
+            distance,
+            is_entry: true,
+            bary: None,
+            uv: None,
+            object_id: 0,
+        })
+    }
+
+    fn surface_area(&self) -> Float {
+        // Sum of the areas of two triangles per grid cell.
+        let scale = self.transform.scale;
+        let cell_x = 2.0 / (self.width - 1) as Float;
+        let cell_y = 2.0 / (self.depth - 1) as Float;
+        let point = |x: usize, y: usize| {
+            vector![
+                x as Float * cell_x,
+                y as Float * cell_y,
+                self.heights[y * self.width + x]
+            ] * scale
+        };
+        let mut area = 0.0;
+        for y in 0..self.depth - 1 {
+            for x in 0..self.width - 1 {
+                let p00 = point(x, y);
+                let p10 = point(x + 1, y);
+                let p01 = point(x, y + 1);
+                let p11 = point(x + 1, y + 1);
+                area += (p10 - p00).cross(p01 - p00).magnitude() * 0.5;
+                area += (p10 - p11).cross(p01 - p11).magnitude() * 0.5;
+            }
+        }
+        area
+    }
+
+    fn bounding_box(&self) -> (Vector<3>, Vector<3>) {
+        self.transform.box_local_to_world(
+            vector![-1, -1, self.min_height],
+            vector![1, 1, self.max_height],
+        )
+    }
+}
+
+/// Image with linear RGB colors, sampled by UV coordinates.
+#[derive(Clone)]
+pub struct Texture {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vector<3>>,
+}
+
+impl Texture {
+    /// Create a texture from `width` by `height` colors in row-major order.
+    pub fn new(width: usize, height: usize, pixels: Vec<Vector<3>>) -> Texture {
+        assert!(width > 0 && height > 0);
+        assert_eq!(pixels.len(), width * height);
+        Texture {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Load a binary (P6) PPM color image. Values are used as-is, without removing any gamma.
+    pub fn from_ppm(path: &str) -> Result<Texture, String> {
+        let (width, height, samples) = read_netpbm(path, "P6", 3)?;
+        let pixels = samples
+            .chunks(3)
+            .map(|c| vector![c[0], c[1], c[2]])
+            .collect();
+        Ok(Texture::new(width, height, pixels))
+    }
+
+    /// Bilinearly interpolated color at UV coordinates, where 0 to 1 spans the image
+    /// from the top left. The image repeats outside that range.
+    pub fn sample(&self, uv: Vector<2>) -> Vector<3> {
+        let x = uv[0] * self.width as Float - 0.5;
+        let y = uv[1] * self.height as Float - 0.5;
+        let (fx, fy) = (x - x.floor(), y - y.floor());
+        let pixel = |dx: Float, dy: Float| {
+            let px = (x.floor() + dx).rem_euclid(self.width as Float) as usize;
+            let py = (y.floor() + dy).rem_euclid(self.height as Float) as usize;
+            self.pixels[py.min(self.height - 1) * self.width + px.min(self.width - 1)]
+        };
+        let top = pixel(0.0, 0.0) * (1.0 - fx) + pixel(1.0, 0.0) * fx;
+        let bottom = pixel(0.0, 1.0) * (1.0 - fx) + pixel(1.0, 1.0) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+/// Structure used by `Scene` to skip objects that a ray can't hit.
+#[derive(Clone)]
+pub enum Accelerator {
+    /// Test every ray against every object.
+    None,
+    Grid(Grid),
+    /// Test every ray against every object, but with a fast check on packed spheres first.
+    /// Only for scenes made entirely of spheres.
+    Spheres(SphereSet),
+}
+
+/// Uniform grid of cells over the scene, each listing the objects whose bounding box overlaps it.
+/// Rays walk through the cells they cross in order and stop at the first cell containing a hit.
+/// Works best when objects are spread evenly; clustered objects end up sharing a few cells.
+#[derive(Clone)]
+pub struct Grid {
+    min: Vector<3>,
+    cell_size: Vector<3>,
+    dims: [usize; 3],
+    cells: Vec<Vec<usize>>,
+    /// Objects without a finite bounding box, which every ray is tested against.
+    unbounded: Vec<usize>,
+}
+
+impl Grid {
+    /// Maximum number of cells along each axis.
+    pub const MAX_DIM: usize = 64;
+
+    /// Bucket objects by bounding box, aiming for a few objects per cell.
+    pub fn new(objects: &[Box<dyn Object + Send + Sync>]) -> Grid {
+        let boxes: Vec<_> = objects.iter().map(|object| object.bounding_box()).collect();
+        let is_finite = |(min, max): &(Vector<3>, Vector<3>)| {
+            min.data()
+                .iter()
+                .chain(max.data().iter())
+                .all(|f| f.is_finite())
+        };
+        let mut min = vector![Float::INFINITY, Float::INFINITY, Float::INFINITY];
+        let mut max = -min;
+        let mut unbounded = Vec::new();
+        for (i, bounds) in boxes.iter().enumerate() {
+            if is_finite(bounds) {
+                min = min.min(bounds.0);
+                max = max.max(bounds.1);
+            } else {
+                unbounded.push(i);
+            }
+        }
+        let bounded = objects.len() - unbounded.len();
+        if bounded == 0 {
+            return Grid {
+                min: vector![0, 0, 0],
+                cell_size: vector![1, 1, 1],
+                dims: [0, 0, 0],
+                cells: Vec::new(),
+                unbounded,
+            };
+        }
+
+        // Pad the bounds so flat scenes still have volume and hits on the edge stay inside.
+        let pad = ((max - min).max_component() * 0.001).max(RAY_EPSILON);
+        let pad = vector![pad, pad, pad];
+        let (min, max) = (min - pad, max + pad);
+        let extent = max - min;
+        let density = (3.0 * bounded as Float / (extent[0] * extent[1] * extent[2])).cbrt();
+        let dims = extent
+            .data()
+            .map(|f| ((f * density).round() as usize).clamp(1, Grid::MAX_DIM));
+        let cell_size = extent / Vector::new(dims.map(|d| d as Float));
+
+        let mut cells = vec![Vec::new(); dims[0] * dims[1] * dims[2]];
+        for (i, bounds) in boxes.iter().enumerate() {
+            if !is_finite(bounds) {
+                continue;
+            }
+            let first = Grid::cell_of(min, cell_size, dims, bounds.0 - pad);
+            let last = Grid::cell_of(min, cell_size, dims, bounds.1 + pad);
+            for z in first[2]..=last[2] {
+                for y in first[1]..=last[1] {
+                    for x in first[0]..=last[0] {
+                        cells[(z * dims[1] + y) * dims[0] + x].push(i);
+                    }
+                }
+            }
+        }
+        Grid {
+            min,
+            cell_size,
+            dims,
+            cells,
+            unbounded,
+        }
+    }
+
+    // Get the cell a point is in, clamped to the grid.
+    fn cell_of(
+        min: Vector<3>,
+        cell_size: Vector<3>,
+        dims: [usize; 3],
+        pos: Vector<3>,
+    ) -> [usize; 3] {
+        let mut cell = [0; 3];
+        for i in 0..3 {
+            let index = ((pos[i] - min[i]) / cell_size[i]).floor().max(0.0) as usize;
+            cell[i] = index.min(dims[i] - 1);
+        }
+        cell
+    }
+
+    /// Call `visit` with the objects of each cell a ray passes through, nearest first,
+    /// along with the distance at which the ray leaves that cell.
+    /// Stops when `visit` returns false. Objects spanning several cells are visited once per cell.
+    pub fn traverse<F: FnMut(&[usize], Float) -> bool>(&self, ray: &Ray, mut visit: F) {
+        if self.cells.is_empty() {
+            return;
+        }
+        let max = self.min + self.cell_size * Vector::new(self.dims.map(|d| d as Float));
+
+        // Clip the ray to the grid bounds.
+        let mut enter: Float = 0.0;
+        let mut exit = Float::INFINITY;
+        for i in 0..3 {
+            if ray.normal[i].abs() < PARALLEL_EPSILON {
+                if ray.pos[i] < self.min[i] || ray.pos[i] > max[i] {
+                    return;
+                }
+            } else {
+                let t0 = (self.min[i] - ray.pos[i]) / ray.normal[i];
+                let t1 = (max[i] - ray.pos[i]) / ray.normal[i];
+                enter = enter.max(t0.min(t1));
+                exit = exit.min(t0.max(t1));
+            }
+        }
+        if enter > exit {
+            return;
+        }
+
+        // Step through the cells with a 3D digital differential analyzer.
+        let start = ray.pos + ray.normal * enter;
+        let mut cell = Grid::cell_of(self.min, self.cell_size, self.dims, start);
+        let mut next = [Float::INFINITY; 3];
+        let mut delta = [Float::INFINITY; 3];
+        for i in 0..3 {
+            if ray.normal[i].abs() >= PARALLEL_EPSILON {
+                let boundary = if ray.normal[i] > 0.0 {
+                    cell[i] + 1
+                } else {
+                    cell[i]
+                };
+                let boundary = self.min[i] + boundary as Float * self.cell_size[i];
+                next[i] = (boundary - ray.pos[i]) / ray.normal[i];
+                delta[i] = self.cell_size[i] / ray.normal[i].abs();
+            }
+        }
+        loop {
+            let axis = (0..3).min_by(|&a, &b| next[a].total_cmp(&next[b])).unwrap();
+            let index = (cell[2] * self.dims[1] + cell[1]) * self.dims[0] + cell[0];
+            if !visit(&self.cells[index], next[axis].min(exit)) || next[axis] > exit {
+                return;
+            }
+            if ray.normal[axis] > 0.0 {
+                cell[axis] += 1;
+                if cell[axis] == self.dims[axis] {
+                    return;
+                }
+            } else {
+                if cell[axis] == 0 {
+                    return;
+                }
+                cell[axis] -= 1;
+            }
+            next[axis] += delta[axis];
+        }
+    }
+}
+
+/// Centers and radii of a scene made only of spheres, packed into arrays so that they can be
+/// checked in a tight loop instead of through a virtual `Object::intersect` call per sphere.
+/// Build it again after moving or resizing any of the spheres.
+#[derive(Clone)]
+pub struct SphereSet {
+    x: Vec<Float>,
+    y: Vec<Float>,
+    z: Vec<Float>,
+    radius_sqr: Vec<Float>,
+    // Radius and scale of each `Sphere`, to measure hits in its local units like it does.
+    radius: Vec<Float>,
+    scale: Vec<Float>,
+}
+
+impl SphereSet {
+    /// Pack the spheres in `objects`, or return None if any object is not a sphere or is a
+    /// sphere that isn't scaled the same along every axis.
+    pub fn new(objects: &[Box<dyn Object + Send + Sync>]) -> Option<SphereSet> {
+        let mut set = SphereSet {
+            x: Vec::with_capacity(objects.len()),
+            y: Vec::with_capacity(objects.len()),
+            z: Vec::with_capacity(objects.len()),
+            radius_sqr: Vec::with_capacity(objects.len()),
+            radius: Vec::with_capacity(objects.len()),
+            scale: Vec::with_capacity(objects.len()),
+        };
+        for object in objects {
+            let sphere = object.as_sphere()?;
+            let scale = sphere.transform.scale();
+            if scale[0] != scale[1] || scale[1] != scale[2] {
+                return None;
+            }
+            let center = sphere.transform.pos();
+            let radius = sphere.radius * scale[0];
+            set.x.push(center[0]);
+            set.y.push(center[1]);
+            set.z.push(center[2]);
+            set.radius_sqr.push(radius * radius);
+            set.radius.push(sphere.radius);
+            set.scale.push(scale[0].abs());
+        }
+        Some(set)
+    }
+
+    /// Intersect sphere `i`, whose current `transform` and `prop` are passed in,
+    /// giving the same result as its `Sphere::intersect`.
+    pub fn intersect(
+        &self,
+        i: usize,
+        ray: &Ray,
+        transform: &Transform,
+        prop: &PhysProp,
+    ) -> Option<Intersect> {
+        let center = vector![self.x[i], self.y[i], self.z[i]];
+        let scale = self.scale[i];
+        let local = Ray {
+            pos: (ray.pos - center) / scale,
+            normal: ray.normal,
+        };
+        let distance = sphere_distance(local, self.radius[i] * self.radius[i])?;
+        let pos = ray.pos + ray.normal * (distance * scale);
+        let normal = (pos - center).as_unit_vector();
+        Some(Intersect {
+            pos,
+            normal,
+            prop: *prop,
+            distance: distance * scale,
+            is_entry: ray.normal.dot(normal) < 0.0,
+            bary: None,
+            uv: Some(direction_to_equirect_uv(
+                transform.normal_world_to_local(normal),
+            )),
+            object_id: 0,
+        })
+    }
+
+    /// Call `visit` with the index of every sphere a ray may hit, in order, along with a lower
+    /// bound on the distance to the hit. Misses are ruled out conservatively, so the spheres
+    /// passed to `visit` still need an exact test.
+    pub fn traverse<F: FnMut(usize, Float)>(&self, ray: &Ray, mut visit: F) {
+        let [px, py, pz] = ray.pos.data();
+        let [dx, dy, dz] = ray.normal.data();
+        // Equal lengths up front let the compiler drop the bounds checks in the loop.
+        let n = self.radius_sqr.len();
+        let (x, y, z) = (&self.x[..n], &self.y[..n], &self.z[..n]);
+        for i in 0..n {
+            let (ox, oy, oz) = (x[i] - px, y[i] - py, z[i] - pz);
+            let a = ox * dx + oy * dy + oz * dz;
+            let b = a * a - (ox * ox + oy * oy + oz * oz) + self.radius_sqr[i];
+            if b < 0.0 {
+                continue;
+            }
+            let root = b.sqrt();
+            if a + root <= 0.0 {
+                continue;
+            }
+            visit(i, (a - root).max(0.0));
+        }
+    }
+}
+
+/// Equirectangular image of the light arriving from every direction,
+/// with a distribution for sampling directions in proportion to their brightness.
+#[derive(Clone)]
+pub struct EnvMap {
+    texture: Texture,
+    // Cumulative distribution of picking each row, ending at 1.
+    row_cdf: Vec<Float>,
+    // Cumulative distribution of picking each pixel within its row, ending at 1 for every row.
+    pixel_cdf: Vec<Float>,
+    // Probability of picking each pixel.
+    pixel_prob: Vec<Float>,
+}
+
+impl EnvMap {
+    /// Build the sampling distribution for an equirectangular `texture`,
+    /// mapped to directions as in `direction_to_equirect_uv`.
+    pub fn new(texture: Texture) -> EnvMap {
+        let (width, height) = (texture.width, texture.height);
+        // Weigh pixels by luminance and by the solid angle they cover, which shrinks at the poles.
+        let mut weights: Vec<Float> = (0..width * height)
+            .map(|i| {
+                let sin_theta = (((i / width) as Float + 0.5) / height as Float * consts::PI).sin();
+                texture.pixels[i]
+                    .dot(vector![0.2126, 0.7152, 0.0722])
+                    .max(0.0)
+                    * sin_theta
+            })
+            .collect();
+        if weights.iter().sum::<Float>() <= 0.0 {
+            weights.fill(1.0);
+        }
+        let total: Float = weights.iter().sum();
+
+        let mut row_cdf = Vec::with_capacity(height);
+        let mut pixel_cdf = Vec::with_capacity(width * height);
+        let mut row_sum = 0.0;
+        for row in weights.chunks(width) {
+            let sum: Float = row.iter().sum();
+            let mut pixel_sum = 0.0;
+            for weight in row {
+                pixel_sum += weight;
+                pixel_cdf.push(if sum > 0.0 { pixel_sum / sum } else { 0.0 });
+            }
+            row_sum += sum;
+            row_cdf.push(row_sum / total);
+        }
+        let pixel_prob = weights.iter().map(|weight| weight / total).collect();
+        EnvMap {
+            texture,
+            row_cdf,
+            pixel_cdf,
+            pixel_prob,
+        }
+    }
+
+    /// Get the light arriving from a direction.
+    pub fn radiance(&self, dir: Vector<3>) -> Vector<3> {
+        self.texture.sample(direction_to_equirect_uv(dir))
+    }
+
+    /// Pick a random direction, more likely where the map is brighter.
+    /// Returns the direction, the light arriving from it and the probability density
+    /// of picking it per unit solid angle.
+    pub fn sample(&self, rng: &mut dyn RngCore) -> (Vector<3>, Vector<3>, Float) {
+        let (width, height) = (self.texture.width, self.texture.height);
+        let pick = |cdf: &[Float], x: Float| cdf.partition_point(|c| *c <= x).min(cdf.len() - 1);
+        let row = pick(&self.row_cdf, rng.gen());
+        let column = pick(&self.pixel_cdf[row * width..(row + 1) * width], rng.gen());
+        let uv = Vector::new([
+            (column as Float + rng.gen::<Float>()) / width as Float,
+            (row as Float + rng.gen::<Float>()) / height as Float,
+        ]);
+        let dir = equirect_uv_to_direction(uv);
+        (dir, self.radiance(dir), self.pdf_at(uv))
+    }
+
+    /// Get the probability density of `sample` picking a direction, per unit solid angle.
+    pub fn pdf(&self, dir: Vector<3>) -> Float {
+        self.pdf_at(direction_to_equirect_uv(dir))
+    }
+
+    fn pdf_at(&self, uv: Vector<2>) -> Float {
+        let (width, height) = (self.texture.width, self.texture.height);
+        let sin_theta = (uv[1] * consts::PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let column = ((uv[0] * width as Float) as usize).min(width - 1);
+        let row = ((uv[1] * height as Float) as usize).min(height - 1);
+        // The image covers 2π by π radians, squeezed by sin(θ) towards the poles.
+        self.pixel_prob[row * width + column] * (width * height) as Float
+            / (2.0 * consts::PI * consts::PI * sin_theta)
+    }
+}
+
+/// How the color of the sky is computed for rays that miss every object.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SkyModel {
+    /// Blend between `ground_color`, `horizon_color` and `skybox_color` by elevation.
+    Gradient,
+    /// Preetham's analytic daylight model, lit by the sun at `sun_direction`.
+    /// `turbidity` is the haziness of the air, from about 2 for a clear sky to 10 for haze.
+    /// `ground_color` is used as the albedo of the ground below the horizon,
+    /// and `sun_color` is reddened by the air the sunlight passes through.
+    Preetham { turbidity: Float },
+    /// No sky, sun or `sky_lights`, for studio renders lit only by emissive objects.
+    /// Rays that miss every object see black, unless there is an `environment` image.
+    Black,
+}
+
+/// Extra bright disk in the procedural sky, such as a moon, blended in like the sun.
+#[derive(Clone, Copy, PartialEq)]
+pub struct SkyLight {
+    /// Unit vector pointing at the light.
+    pub direction: Vector<3>,
+    /// Dot product threshold for a ray to be pointing at the light.
+    pub radius: Float,
+    pub color: Vector<3>,
+}
+
+#[derive(Clone)]
+pub struct Scene {
+    /// List of objects in the scene.
+    pub objects: Vec<Box<dyn Object + Send + Sync>>,
+    /// Images referenced by index from `PhysProp`.
+    pub textures: Vec<Texture>,
+    /// Ground color.
+    pub ground_color: Vector<3>,
+    /// Horizon color.
+    pub horizon_color: Vector<3>,
+    /// Skybox color.
+    pub skybox_color: Vector<3>,
+    /// Sun color.
+    pub sun_color: Vector<3>,
+    /// Unit vector pointing at the sun.
+    pub sun_direction: Vector<3>,
+    /// Dot product threshold for a ray to be pointing at the sun.
+    pub sun_radius: Float,
+    /// Lights in the sky besides the sun. Not drawn over an `environment` image.
+    pub sky_lights: Vec<SkyLight>,
+    /// Materials that objects can refer to by name, see `resolve_materials`.
+    pub materials: HashMap<String, PhysProp>,
+    /// Indices into `objects` of the emissive objects that diffuse surfaces sample directly,
+    /// see `find_lights`.
+    pub lights: Vec<usize>,
+    pub sky_model: SkyModel,
+    /// Image of the surroundings, used for rays that miss every object instead of the sky.
+    pub environment: Option<EnvMap>,
+    /// Density of the height fog at Y of 0, or 0 for no fog.
+    /// The fog hides surfaces seen by the camera by `1 - exp(-density * distance)`.
+    pub fog_density: Float,
+    /// How quickly the fog thins out going up, in the exponent per unit of height.
+    pub fog_falloff: Float,
+    pub fog_color: Vector<3>,
+    /// Acceleration structure for raycasts, which must be rebuilt after changing `objects`.
+    pub accelerator: Accelerator,
+}
+
+impl Scene {
+    pub fn empty() -> Scene {
+        Scene {
+            objects: Vec::new(),
+            textures: Vec::new(),
+            ground_color: vector![0, 0, 0],
+            horizon_color: vector![0, 0, 0],
+            skybox_color: vector![0, 0, 0],
+            sun_color: vector![0, 0, 0],
+            sun_direction: vector![0, -1, 0],
+            sun_radius: 1.0,
+            sky_lights: Vec::new(),
+            materials: HashMap::new(),
+            lights: Vec::new(),
+            sky_model: SkyModel::Gradient,
+            environment: None,
+            fog_density: 0.0,
+            fog_falloff: 0.0,
+            fog_color: vector![0, 0, 0],
+            accelerator: Accelerator::None,
+        }
+    }
+
+    /// Point `sun_direction` at the sun at `elevation` degrees above the horizon,
+    /// turned `azimuth` degrees from the Z axis towards the X axis.
+    pub fn set_sun(&mut self, azimuth: Float, elevation: Float) {
+        let (azimuth, elevation) = (azimuth.to_radians(), elevation.to_radians());
+        self.sun_direction = vector![
+            elevation.cos() * azimuth.sin(),
+            -elevation.sin(),
+            elevation.cos() * azimuth.cos()
+        ];
+    }
+
+    /// Use a `Grid` built from the current objects for raycasts.
+    pub fn build_grid(&mut self) {
+        self.accelerator = Accelerator::Grid(Grid::new(&self.objects));
+    }
+
+    /// Switch to `Accelerator::Spheres` if the scene is made only of uniformly scaled spheres,
+    /// returning whether it did. Other scenes keep their current accelerator.
+    pub fn build_sphere_set(&mut self) -> bool {
+        match SphereSet::new(&self.objects) {
+            Some(spheres) => {
+                self.accelerator = Accelerator::Spheres(spheres);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Check the scene for mistakes that would silently break rendering.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        let sun_error = (self.sun_direction.magnitude() - 1.0).abs();
+        if sun_error.is_nan() || sun_error >= 0.000001 {
+            errors.push(format!(
+                "sun_direction {:?} is not a unit vector",
+                self.sun_direction.data()
+            ));
+        }
+        for (i, light) in self.sky_lights.iter().enumerate() {
+            let error = (light.direction.magnitude() - 1.0).abs();
+            if error.is_nan() || error >= 0.000001 {
+                errors.push(format!(
+                    "sky light {}: direction {:?} is not a unit vector",
+                    i,
+                    light.direction.data()
+                ));
+            }
+        }
+        for &light in &self.lights {
+            if light >= self.objects.len() {
+                errors.push(format!("light {} is not an index into objects", light));
+            }
+        }
+        for (i, object) in self.objects.iter().enumerate() {
+            let scale = object.transform().scale();
+            if let Some(name) = object.material() {
+                if !self.materials.contains_key(name) {
+                    errors.push(format!("object {}: material {:?} is not defined", i, name));
+                }
+            }
+            if !scale.data().iter().all(|f| *f > MIN_SCALE) {
+                errors.push(format!(
+                    "object {}: scale {:?} has a zero, negative or NaN component",
+                    i,
+                    scale.data()
+                ));
+            }
+            for error in object.validate() {
+                errors.push(format!("object {}: {}", i, error));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+    /// Give every object that names a material a copy of it from `materials`.
+    /// Call this after building the scene and before rendering; unknown names are left as they
+    /// are, and reported by `validate`.
+    pub fn resolve_materials(&mut self) {
+        for object in &mut self.objects {
+            let prop = object.material().and_then(|name| self.materials.get(name));
+            if let Some(prop) = prop {
+                object.set_prop(*prop);
+            }
+        }
+    }
+    /// Set `lights` to every enabled, emissive object that can pick points on its surface.
+    /// Call this after building the scene and resolving its materials.
+    pub fn find_lights(&mut self) {
+        // Whether an object can pick points doesn't depend on the random numbers.
+        let mut rng = rand::thread_rng();
+        self.lights = (0..self.objects.len())
+            .filter(|&i| {
+                let object = &self.objects[i];
+                let prop = object.prop();
+                let emissive =
+                    prop.emission.max_component() > 0.0 || prop.emission_texture.is_some();
+                object.enabled() && emissive && object.sample_point(&mut rng).is_some()
+            })
+            .collect();
+    }
+    /// Get the light emitted at an intersection, from its emission texture if it has one.
+    pub fn emission(&self, intersect: &Intersect) -> Vector<3> {
+        let texture = intersect
+            .prop
+            .emission_texture
+            .and_then(|i| self.textures.get(i));
+        match (texture, intersect.uv) {
+            (Some(texture), Some(uv)) => texture.sample(uv) * intersect.prop.emission_strength,
+            _ => intersect.prop.emission,
+        }
+    }
+
+    /// Get the bounding box of all enabled objects, as minimum and maximum corners,
+    /// or `None` if there are none.
+    pub fn bounding_box(&self) -> Option<(Vector<3>, Vector<3>)> {
+        let mut enabled = self.objects.iter().filter(|object| object.enabled());
+        let mut bounds = enabled.next()?.bounding_box();
+        for object in enabled {
+            let (min, max) = object.bounding_box();
+            bounds = (bounds.0.min(min), bounds.1.max(max));
+        }
+        Some(bounds)
+    }
+
+    /// Get the closest intersection of a ray with the scene, if any.
+    /// Objects decide `Intersect::is_entry` from their geometric surface: a ray enters a solid
+    /// where it runs against the outward surface, and every hit on an open surface is an entry.
+    /// Hits within `COINCIDENT_EPSILON` of each other are a tie, won by the object with the
+    /// lowest index in `objects` regardless of the order objects are tested in, so coincident
+    /// surfaces don't flicker between frames or with the accelerator.
+    pub fn raycast(&self, ray: Ray) -> Option<Intersect> {
+        self.raycast_filtered(ray, |_| true).0
+    }
+
+    /// Get the closest intersection of a ray with the objects that are visible to `kind` of ray.
+    pub fn raycast_visible(&self, ray: Ray, kind: RayKind) -> Option<Intersect> {
+        self.raycast_visible_counted(ray, kind).0
+    }
+
+    /// Like `raycast_visible`, but also returns the number of objects the ray was tested against.
+    pub fn raycast_visible_counted(&self, ray: Ray, kind: RayKind) -> (Option<Intersect>, u64) {
+        self.raycast_filtered(ray, |intersect| intersect.prop.visibility.is_visible(kind))
+    }
+
+    fn raycast_filtered<F: Fn(&Intersect) -> bool>(
+        &self,
+        ray: Ray,
+        filter: F,
+    ) -> (Option<Intersect>, u64) {
+        let mut tests = 0;
+        let mut test = |i: usize, out: &mut Option<Intersect>| {
+            let object = &self.objects[i];
+            if !object.enabled() {
+                return;
+            }
+            tests += 1;
+            if let Some(mut intersect) = self.intersect_object(i, ray).filter(&filter) {
+                intersect.object_id = i;
+                // Objects measure distance in their local space, which is scaled.
+                intersect.distance = (intersect.pos - ray.pos).magnitude();
+                if out.is_none_or(|cur| Scene::is_closer(&intersect, &cur)) {
+                    *out = Some(intersect);
+                }
+            }
+        };
+        let mut out: Option<Intersect> = None;
+        match &self.accelerator {
+            Accelerator::None => {
+                for i in 0..self.objects.len() {
+                    test(i, &mut out);
+                }
+            }
+            Accelerator::Grid(grid) => {
+                for &i in &grid.unbounded {
+                    test(i, &mut out);
+                }
+                // A hit before the ray leaves a cell can't be beaten by objects in later cells.
+                grid.traverse(&ray, |cell, exit| {
+                    for &i in cell {
+                        test(i, &mut out);
+                    }
+                    // Leave room for ties with objects in later cells.
+                    out.is_none_or(|hit| hit.distance * (1.0 - COINCIDENT_EPSILON) > exit)
+                });
+            }
+            Accelerator::Spheres(spheres) => {
+                spheres.traverse(&ray, |i, near| {
+                    if out.is_none_or(|hit| near * (1.0 - COINCIDENT_EPSILON) <= hit.distance) {
+                        test(i, &mut out);
+                    }
+                });
+            }
+        }
+        (out, tests)
+    }
+
+    // Whether hit `a` takes precedence over hit `b`: the closer one wins, but when the distances
+    // are nearly equal, the one from the object listed first.
+    fn is_closer(a: &Intersect, b: &Intersect) -> bool {
+        let tolerance = a.distance.max(b.distance) * COINCIDENT_EPSILON;
+        if (a.distance - b.distance).abs() <= tolerance {
+            a.object_id < b.object_id
+        } else {
+            a.distance < b.distance
+        }
+    }
+
+    // Intersect object `i`, straight from the packed arrays if they hold it.
+    fn intersect_object(&self, i: usize, ray: Ray) -> Option<Intersect> {
+        let object = &*self.objects[i];
+        match &self.accelerator {
+            Accelerator::Spheres(spheres) if object.prop().cutout_texture.is_none() => {
+                spheres.intersect(i, &ray, object.transform(), object.prop())
+            }
+            _ => self.intersect_uncut(object, ray),
+        }
+    }
+
+    // Intersect an object, skipping over hits on parts removed by its cutout texture.
+    // Gives up after `MAX_CUTOUT_LAYERS` skipped hits so a ray can't get stuck re-hitting
+    // the same spot.
+    fn intersect_uncut(&self, object: &dyn Object, mut ray: Ray) -> Option<Intersect> {
+        for _ in 0..MAX_CUTOUT_LAYERS {
+            let intersect = object.intersect(&ray)?;
+            let mask = intersect
+                .prop
+                .cutout_texture
+                .and_then(|i| self.textures.get(i));
+            match (mask, intersect.uv) {
+                (Some(mask), Some(uv)) if mask.sample(uv)[0] < intersect.prop.cutout_threshold => {
+                    ray.pos = intersect.pos;
+                }
+                _ => return Some(intersect),
+            }
+        }
+        None
+    }
+
+    /// Get the closest intersection of each of `rays` with the scene.
+    pub fn raycast_many(&self, rays: &[Ray]) -> Vec<Option<Intersect>> {
+        rays.iter().map(|ray| self.raycast(*ray)).collect()
+    }
+}
+
+/// Read a binary Netpbm image with the given magic number and number of channels,
+/// returning its width, height and samples scaled to the range 0 to 1.
+fn read_netpbm(
+    path: &str,
+    magic: &str,
+    channels: usize,
+) -> Result<(usize, usize, Vec<Float>), String> {
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+
+    // Header: magic, width, height and maximum value, separated by whitespace and comments.
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b'#') {
+            if data[pos] == b'#' {
+                while pos < data.len() && data[pos] != b'\n' {
+                    pos += 1;
+                }
+            } else {
+                pos += 1;
+            }
+        }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(format!("Truncated {} header", magic));
+        }
+        fields.push(String::from_utf8_lossy(&data[start..pos]).to_string());
+    }
+    if fields[0] != magic {
+        return Err(format!("Not a {} image", magic));
+    }
+    let parse = |s: &str| s.parse::<usize>().map_err(|e| e.to_string());
+    let width = parse(&fields[1])?;
+    let height = parse(&fields[2])?;
+    let max_value = parse(&fields[3])?;
+    if width == 0 || height == 0 || max_value == 0 || max_value > 65535 {
+        return Err(format!("Unsupported {} dimensions or maximum value", magic));
+    }
+
+    // Pixel data starts after a single whitespace character.
+    let pixels = &data[(pos + 1).min(data.len())..];
+    let sample_size = if max_value > 255 { 2 } else { 1 };
+    let count = width * height * channels;
+    if pixels.len() < count * sample_size {
+        return Err(format!("Truncated {} pixel data", magic));
+    }
+    let samples = pixels
+        .chunks(sample_size)
+        .take(count)
+        .map(|c| {
+            let value = if sample_size == 2 {
+                (c[0] as usize) << 8 | c[1] as usize
+            } else {
+                c[0] as usize
+            };
+            value as Float / max_value as Float
+        })
+        .collect();
+    Ok((width, height, samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::time::Instant;
+
+    fn triangle() -> Triangle {
+        Triangle {
+            transform: Transform::from(vector![0, 0, 2], vector![1, 1, 1], vector![0, 0, 0]),
+            enabled: true,
+            material: None,
+            vertices: [vector![-1, 1, 0], vector![1, 1, 0], vector![0, -1, 0]],
+            normals: None,
+            prop: PhysProp::from_color(vector![1, 0, 0]),
+        }
+    }
+
+    #[test]
+    fn triangle_centroid_hit_has_equal_barycentrics() {
+        let tri = triangle();
+        let centroid = (tri.vertices[0] + tri.vertices[1] + tri.vertices[2]) / 3.0;
+        let ray = Ray {
+            pos: vector![centroid[0], centroid[1], 0],
+            normal: vector![0, 0, 1],
+        };
+        let bary = tri.intersect(&ray).unwrap().bary.unwrap();
+        for i in 0..3 {
+            assert!(
+                (bary[i] - 1.0 / 3.0).abs() < 1e-5,
+                "bary[{}] = {}",
+                i,
+                bary[i]
+            );
+        }
+    }
+
+    #[test]
+    fn quad_edge_test_follows_slanted_edges() {
+        // A trapezoid, so points inside its bounding box can still be outside it.
+        let quad = Quad {
+            transform: Transform::from(vector![0, 0, 2], vector![1, 1, 1], vector![0, 0, 0]),
+            enabled: true,
+            material: None,
+            vertices: [
+                vector![-1, -1, 0],
+                vector![1, -1, 0],
+                vector![0.5, 1, 0],
+                vector![-0.5, 1, 0],
+            ],
+            prop: PhysProp::from_color(vector![1, 1, 1]),
+        };
+        let ray_at = |x: Float, y: Float| Ray {
+            pos: vector![x, y, 0],
+            normal: vector![0, 0, 1],
+        };
+        let hit = quad.intersect(&ray_at(0.0, 0.0)).unwrap();
+        assert!((hit.distance - 2.0).abs() < 1e-5);
+        assert!(hit.normal == vector![0, 0, -1]);
+        assert!(quad.intersect(&ray_at(0.5, 0.9)).is_some());
+        assert!(quad.intersect(&ray_at(0.9, 0.9)).is_none());
+        assert!(quad.intersect(&ray_at(-0.9, 0.9)).is_none());
+        assert!(quad.intersect(&ray_at(0.0, 1.1)).is_none());
+    }
+
+    #[test]
+    fn height_field_hit_follows_the_slope() {
+        // A ramp rising from height 0 at X = -1 to height 1 at X = 1.
+        let field = HeightField::new(
+            Transform::from(vector![0, 0, 0], vector![1, 1, 1], vector![0, 0, 0]),
+            2,
+            2,
+            vec![0.0, 2.0, 0.0, 2.0],
+            0.5,
+            PhysProp::from_color(vector![1, 1, 1]),
+        );
+        assert!((field.height(0.0, 0.3) - 0.5).abs() < 1e-5);
+        let ray = Ray {
+            pos: vector![0, 0, 5],
+            normal: vector![0, 0, -1],
+        };
+        let hit = field.intersect(&ray).unwrap();
+        assert!((hit.distance - 4.5).abs() < 1e-4);
+        assert!((hit.pos[2] - 0.5).abs() < 1e-4);
+        let expected = vector![-0.5, 0, 1].as_unit_vector();
+        assert!((hit.normal - expected).magnitude() < 1e-4);
+        let outside = Ray {
+            pos: vector![1.5, 0, 5],
+            normal: vector![0, 0, -1],
+        };
+        assert!(field.intersect(&outside).is_none());
+    }
+
+    #[test]
+    fn plane_back_face_uses_back_material() {
+        // A floor with its front face pointing up, which is -Y.
+        let front = PhysProp::from_color(vector![1, 0, 0]);
+        let back = PhysProp::from_color(vector![0, 0, 1]);
+        let floor = Plane {
+            transform: Transform::from(vector![0, 0.5, 2], vector![1, 1, 1], vector![-90, 0, 0]),
+            enabled: true,
+            material: None,
+            prop: front,
+            back_prop: Some(back),
+        };
+        let from_above = Ray {
+            pos: vector![0, -1, 2],
+            normal: vector![0, 1, 0],
+        };
+        let from_below = Ray {
+            pos: vector![0, 2, 2],
+            normal: vector![0, -1, 0],
+        };
+        assert!(floor.intersect(&from_above).unwrap().prop == front);
+        assert!(floor.intersect(&from_below).unwrap().prop == back);
+        let single = Plane {
+            back_prop: None,
+            ..floor
+        };
+        assert!(single.intersect(&from_below).unwrap().prop == front);
+    }
+
+    #[test]
+    fn zero_scale_is_clamped_and_reported() {
+        let transform = Transform::from(vector![0, 0, 2], vector![0, 1, 1], vector![0, 0, 0]);
+        assert!(*transform.scale() == vector![MIN_SCALE, 1, 1]);
+        let local = transform.world_to_local(vector![1, 1, 1]);
+        assert!(local.data().iter().all(|f| f.is_finite()));
+        let flipped = Transform::from(vector![0, 0, 0], vector![-0.0, 1, 1], vector![0, 0, 0]);
+        assert!(*flipped.scale() == vector![-MIN_SCALE, 1, 1]);
+
+        let mut scene = Scene::empty();
+        scene.objects.push(Box::new(Sphere {
+            transform,
+            enabled: true,
+            material: None,
+            radius: 1.0,
+            prop: PhysProp::from_color(vector![1, 1, 1]),
+        }));
+        let errors = scene.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("scale"), "{}", errors[0]);
+    }
+
+    fn assert_near(a: Vector<3>, b: Vector<3>) {
+        let error = (a - b).magnitude();
+        assert!(
+            error < 1e-4,
+            "off by {}: {:?} vs {:?}",
+            error,
+            a.data(),
+            b.data()
+        );
+    }
+
+    #[test]
+    fn inverse_undoes_transform() {
+        let t = Transform::from(vector![1, -2, 3], vector![2, 2, 2], vector![30, 45, 60]);
+        let inverse = t.inverse();
+        for p in [vector![0, 0, 0], vector![1, 2, 3], vector![-4, 0.5, 7]] {
+            assert_near(inverse.local_to_world(t.local_to_world(p)), p);
+            assert_near(t.local_to_world(inverse.local_to_world(p)), p);
+        }
+    }
+
+    #[test]
+    fn combine_matches_applying_parent_after_child() {
+        let parent = Transform::from(vector![1, 2, 3], vector![2, 2, 2], vector![0, 90, 0]);
+        let child = Transform::from(vector![0, 1, 0], vector![1, 1, 1], vector![45, 0, 0]);
+        let combined = parent.combine(&child);
+        for p in [vector![0, 0, 0], vector![1, 0, 0], vector![0.5, -1, 2]] {
+            assert_near(
+                combined.local_to_world(p),
+                parent.local_to_world(child.local_to_world(p)),
+            );
+        }
+    }
+
+    #[test]
+    fn sphere_uv_is_continuous_at_poles_and_seam() {
+        let sphere = Sphere {
+            transform: Transform::from(vector![0, 0, 0], vector![1, 1, 1], vector![0, 0, 0]),
+            enabled: true,
+            material: None,
+            radius: 2.0,
+            prop: PhysProp::from_color(vector![1, 1, 1]),
+        };
+        let uv_towards = |dir: Vector<3>| {
+            let ray = Ray {
+                pos: dir * 10.0,
+                normal: -dir,
+            };
+            sphere.intersect(&ray).unwrap().uv.unwrap()
+        };
+        // The poles map to V 0 at the top, which is -Y, and 1 at the bottom.
+        let top = uv_towards(vector![0, -1, 0]);
+        assert!(top[1].abs() < 1e-5);
+        let bottom = uv_towards(vector![0, 1, 0]);
+        assert!((bottom[1] - 1.0).abs() < 1e-5);
+        // Nearly at the pole, V is nearly 0 whichever way U points.
+        for dir in [
+            vector![0.001, -1, 0],
+            vector![0, -1, -0.001],
+            vector![-0.001, -1, 0.001],
+        ] {
+            assert!(uv_towards(dir.as_unit_vector())[1] < 0.001);
+        }
+        // Either side of the seam towards -Z, U wraps from 1 back to 0.
+        let left = uv_towards(vector![-0.001, 0, -1].as_unit_vector());
+        let right = uv_towards(vector![0.001, 0, -1].as_unit_vector());
+        let gap = (left[0] - right[0]).abs();
+        assert!(gap.min(1.0 - gap) < 0.001, "{} and {}", left[0], right[0]);
+        assert!((left[1] - 0.5).abs() < 0.001 && (right[1] - 0.5).abs() < 0.001);
+        // Facing +Z is the middle of the map.
+        let front = uv_towards(vector![0, 0, 1]);
+        assert!((front[0] - 0.5).abs() < 1e-5 && (front[1] - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn four_quarter_turns_return_to_the_start() {
+        let start = Transform::from(vector![1, 2, 3], vector![1, 1, 1], vector![20, 30, 40]);
+        let mut t = start;
+        for _ in 0..4 {
+            t.rotate_by(vector![0, 1, 0], 90.0);
+        }
+        assert_near(t.right(), start.right());
+        assert_near(t.up(), start.up());
+        assert_near(t.forward(), start.forward());
+        // One quarter turn around the local Y axis takes forward to where right was.
+        let mut quarter = start;
+        quarter.rotate_by(vector![0, 1, 0], 90.0);
+        assert_near(quarter.up(), start.up());
+        assert_near(quarter.forward().cross(start.right()), vector![0, 0, 0]);
+    }
+
+    #[test]
+    fn identity_basis_is_the_canonical_axes() {
+        let t = Transform::identity();
+        assert!(t.right() == vector![1, 0, 0]);
+        // +Y is down, so up is -Y.
+        assert!(t.up() == vector![0, -1, 0]);
+        assert!(t.forward() == vector![0, 0, 1]);
+        let turned = Transform::from(vector![0, 0, 0], vector![1, 1, 1], vector![0, 90, 0]);
+        assert_near(turned.right().cross(turned.forward()), turned.up());
+    }
+
+    #[test]
+    fn disabled_object_is_not_hit() {
+        let sphere_at = |z: Float| Sphere {
+            transform: Transform::from(vector![0, 0, z], vector![1, 1, 1], vector![0, 0, 0]),
+            enabled: true,
+            material: None,
+            radius: 0.5,
+            prop: PhysProp::from_color(vector![1, 1, 1]),
+        };
+        let mut scene = Scene::empty();
+        scene.objects.push(Box::new(sphere_at(2.0)));
+        scene.objects.push(Box::new(sphere_at(5.0)));
+        scene.objects[0].set_enabled(false);
+        let ray = Ray {
+            pos: vector![0, 0, 0],
+            normal: vector![0, 0, 1],
+        };
+        for grid in [false, true] {
+            if grid {
+                scene.build_grid();
+            }
+            let hit = scene.raycast(ray).unwrap();
+            assert_eq!(hit.object_id, 1);
+            assert!((hit.distance - 4.5).abs() < 1e-5);
+        }
+        let (min, _) = scene.bounding_box().unwrap();
+        assert!((min[2] - 4.5).abs() < 1e-5);
+        scene.objects[1].set_enabled(false);
+        assert!(scene.raycast(ray).is_none());
+        assert!(scene.bounding_box().is_none());
+    }
+
+    #[test]
+    fn ray_leaving_a_sphere_surface_does_not_hit_it() {
+        let sphere = Sphere {
+            transform: Transform::from(vector![1, 2, 3], vector![2, 2, 2], vector![10, 20, 30]),
+            enabled: true,
+            material: None,
+            radius: 0.5,
+            prop: PhysProp::from_color(vector![1, 1, 1]),
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            // A point on the surface, in world space, and the outward normal there.
+            let normal = Vector::<3>::random_unit_vector(&mut rng);
+            let pos = vector![1, 2, 3] + normal;
+            let outward = Ray {
+                pos,
+                normal: (normal + Vector::<3>::random_unit_vector(&mut rng) * 0.9).as_unit_vector(),
+            };
+            assert!(sphere.intersect(&outward).is_none());
+            // Going back in, it crosses the sphere and leaves through the far side.
+            let inward = Ray {
+                pos,
+                normal: -outward.normal,
+            };
+            let hit = sphere.intersect(&inward).unwrap();
+            assert!(!hit.is_entry);
+            assert!(hit.distance > 0.01);
+        }
+    }
+
+    // A scene of `count` randomly placed, sized and rotated spheres inside a 20 unit cube.
+    fn random_spheres(count: usize, rng: &mut StdRng) -> Scene {
+        let mut scene = Scene::empty();
+        for _ in 0..count {
+            let pos = vector![
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0)
+            ];
+            let scale = rng.gen_range(0.5..2.0);
+            let angle = Vector::<3>::random_unit_vector(rng) * 180.0;
+            scene.objects.push(Box::new(Sphere {
+                transform: Transform::from(pos, vector![scale, scale, scale], angle),
+                enabled: true,
+                material: None,
+                radius: rng.gen_range(0.05..0.5),
+                prop: PhysProp::from_color(vector![1, 1, 1]),
+            }));
+        }
+        scene
+    }
+
+    // Check that two raycasts found the same hit.
+    fn assert_same_hit(a: Option<Intersect>, b: Option<Intersect>) {
+        assert_eq!(a.is_some(), b.is_some());
+        let (Some(a), Some(b)) = (a, b) else {
+            return;
+        };
+        assert_eq!(a.object_id, b.object_id);
+        assert_eq!(a.is_entry, b.is_entry);
+        // Loose enough for rounding in single precision on small spheres.
+        assert!((a.distance - b.distance).abs() < 1e-3);
+        assert!((a.pos - b.pos).magnitude() < 1e-3);
+        assert!((a.normal - b.normal).magnitude() < 1e-2);
+        let (uv_a, uv_b) = (a.uv.unwrap(), b.uv.unwrap());
+        // Away from the seam, where u wraps around.
+        if (uv_a[0] - uv_b[0]).abs() < 0.5 {
+            assert!((uv_a - uv_b).magnitude() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn sphere_set_matches_intersecting_each_sphere() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut scene = random_spheres(200, &mut rng);
+        scene.objects[3].set_enabled(false);
+        let rays: Vec<Ray> = (0..2000)
+            .map(|i| {
+                let normal = Vector::<3>::random_unit_vector(&mut rng);
+                if i % 2 == 0 {
+                    let pos = Vector::<3>::random_unit_vector(&mut rng) * 8.0;
+                    return Ray { pos, normal };
+                }
+                // Starting on a surface, like a bounce does.
+                let hit = scene.raycast(Ray {
+                    pos: vector![0, 0, 0],
+                    normal,
+                });
+                let pos = hit.map_or(vector![0, 0, 0], |hit| hit.pos);
+                Ray {
+                    pos,
+                    normal: Vector::<3>::random_unit_vector(&mut rng),
+                }
+            })
+            .collect();
+        let expected: Vec<_> = rays.iter().map(|ray| scene.raycast(*ray)).collect();
+        assert!(scene.build_sphere_set());
+        for (ray, expected) in rays.iter().zip(expected) {
+            let actual = scene.raycast(*ray);
+            // With single precision, a ray starting on a small sphere far from the origin can
+            // hit that sphere again right away, in either path.
+            let acne = |hit: &Option<Intersect>| hit.is_some_and(|hit| hit.distance < 1e-3);
+            if !acne(&actual) && !acne(&expected) {
+                assert_same_hit(actual, expected);
+            }
+        }
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture` to see the timings.
+    #[test]
+    #[ignore]
+    fn sphere_set_timing_with_10k_spheres() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut scene = random_spheres(10_000, &mut rng);
+        let rays: Vec<Ray> = (0..20_000)
+            .map(|_| Ray {
+                pos: Vector::<3>::random_unit_vector(&mut rng) * 15.0,
+                normal: Vector::<3>::random_unit_vector(&mut rng),
+            })
+            .collect();
+        let start = Instant::now();
+        let expected: Vec<_> = rays.iter().map(|ray| scene.raycast(*ray)).collect();
+        let generic = start.elapsed();
+        assert!(scene.build_sphere_set());
+        let start = Instant::now();
+        let actual: Vec<_> = rays.iter().map(|ray| scene.raycast(*ray)).collect();
+        let packed = start.elapsed();
+        println!(
+            "{} rays against 10k spheres: {:?} generic, {:?} packed, {:.1}x",
+            rays.len(),
+            generic,
+            packed,
+            generic.as_secs_f64() / packed.as_secs_f64()
+        );
+        for (actual, expected) in actual.into_iter().zip(expected) {
+            assert_same_hit(actual, expected);
+        }
+    }
+
+    #[test]
+    fn coincident_planes_go_to_the_first_listed() {
+        let plane = |color: Vector<3>| Plane {
+            transform: Transform::from(vector![0, 0, 3], vector![2, 2, 2], vector![0, 0, 0]),
+            enabled: true,
+            material: None,
+            prop: PhysProp::from_color(color),
+            back_prop: None,
+        };
+        let (red, blue) = (vector![1, 0, 0], vector![0, 0, 1]);
+        let mut rng = StdRng::seed_from_u64(0);
+        for (first, second) in [(red, blue), (blue, red)] {
+            let mut scene = Scene::empty();
+            scene.objects.push(Box::new(plane(first)));
+            scene.objects.push(Box::new(plane(second)));
+            for grid in [false, true] {
+                if grid {
+                    scene.build_grid();
+                }
+                for _ in 0..100 {
+                    let target = vector![rng.gen_range(-1.9..1.9), rng.gen_range(-1.9..1.9), 3];
+                    let ray = Ray {
+                        pos: vector![0, 0, 0],
+                        normal: target.as_unit_vector(),
+                    };
+                    let hit = scene.raycast(ray).unwrap();
+                    assert_eq!(hit.object_id, 0);
+                    assert!(hit.prop.color == first);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn surface_area_matches_analytic_values() {
+        let sphere = |scale: Float| Sphere {
+            transform: Transform::from(
+                vector![1, 2, 3],
+                vector![scale, scale, scale],
+                vector![10, 20, 30],
+            ),
+            enabled: true,
+            material: None,
+            radius: 1.0,
+            prop: PhysProp::from_color(vector![1, 1, 1]),
+        };
+        for scale in [1.0, 0.5, 3.0] {
+            let expected = 4.0 * PI * scale * scale;
+            let area = sphere(scale).surface_area();
+            assert!(
+                (area / expected - 1.0).abs() < 1e-4,
+                "{} vs {}",
+                area,
+                expected
+            );
+        }
+        // A plane spans -1 to 1 on its local X and Y axes.
+        let plane = Plane {
+            transform: Transform::from(vector![0, 0, 0], vector![3, 0.5, 7], vector![90, 0, 45]),
+            enabled: true,
+            material: None,
+            prop: PhysProp::from_color(vector![1, 1, 1]),
+            back_prop: None,
+        };
+        assert!((plane.surface_area() - 4.0 * 3.0 * 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn normals_stay_unit_and_perpendicular_under_non_uniform_scale() {
+        let transform = Transform::from(vector![1, 2, 3], vector![3, 0.5, 1], vector![20, 30, 40]);
+        let prop = PhysProp::from_color(vector![1, 1, 1]);
+        let square = [
+            vector![-1, -1, 0],
+            vector![1, -1, 0],
+            vector![1, 1, 0],
+            vector![-1, 1, 0],
+        ];
+        // Each object with a local point on its surface and a local direction to look at it from.
+        let objects: Vec<(Box<dyn Object>, Vector<3>, Vector<3>)> = vec![
+            (
+                Box::new(Sphere {
+                    transform,
+                    enabled: true,
+                    material: None,
+                    radius: 1.0,
+                    prop,
+                }),
+                vector![0.3, -0.4, -(0.75 as Float).sqrt()],
+                vector![0.3, -0.4, -(0.75 as Float).sqrt()],
+            ),
+            (
+                Box::new(Plane {
+                    transform,
+                    enabled: true,
+                    material: None,
+                    prop,
+                    back_prop: None,
+                }),
+                vector![0.2, 0.3, 0],
+                vector![0.3, 0.2, -1],
+            ),
+            (
+                Box::new(Triangle {
+                    transform,
+                    ..triangle()
+                }),
+                vector![0.0, 1.0 / 3.0, 0.0],
+                vector![0.2, 0.1, -1],
+            ),
+            (
+                Box::new(Quad {
+                    transform,
+                    enabled: true,
+                    material: None,
+                    vertices: square,
+                    prop,
+                }),
+                vector![0.1, 0.2, 0],
+                vector![0.1, 0.3, -1],
+            ),
+            (
+                Box::new(HeightField::new(
+                    transform,
+                    2,
+                    2,
+                    vec![0.0, 2.0, 0.0, 2.0],
+                    0.5,
+                    prop,
+                )),
+                vector![0, 0.3, 0.5],
+                vector![0.1, 0.2, 1],
+            ),
+        ];
+        for (i, (object, point, dir)) in objects.into_iter().enumerate() {
+            let hit_near = |offset: Vector<3>| {
+                let target = transform.local_to_world(point + offset);
+                let pos = transform.local_to_world(point + offset + dir);
+                let ray = Ray {
+                    pos,
+                    normal: (target - pos).as_unit_vector(),
+                };
+                object.intersect(&ray).unwrap()
+            };
+            let hit = hit_near(vector![0, 0, 0]);
+            assert!((hit.normal.magnitude() - 1.0).abs() < 1e-4);
+            for offset in [vector![0.001, 0, 0], vector![0, 0.001, 0]] {
+                let tangent = (hit_near(offset).pos - hit.pos).as_unit_vector();
+                let dot = hit.normal.dot(tangent);
+                assert!(
+                    dot.abs() < 1e-2,
+                    "normal {} is off the surface by {}",
+                    i,
+                    dot
+                );
+            }
         }
     }
 }
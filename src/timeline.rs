@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+
+use crate::matrix::*;
+use crate::scene::*;
+
+#[derive(Clone, Copy)]
+pub struct Keyframe {
+    /// Time of the keyframe in seconds.
+    pub time: Float,
+    pub transform: Transform,
+}
+
+/// Keyframed transforms of scene objects, for scripted animation.
+/// For `Tracer::render_animation`, `apply` it to each frame's scene at the frame index divided by
+/// the frame rate.
+#[derive(Clone, Default)]
+pub struct Timeline {
+    // Keyframes sorted by time, per index into `Scene::objects`.
+    tracks: BTreeMap<usize, Vec<Keyframe>>,
+}
+
+impl Timeline {
+    pub fn new() -> Timeline {
+        Timeline::default()
+    }
+
+    /// Set the transform of the object at index `id` in `Scene::objects` at `time` in seconds,
+    /// replacing any keyframe already at that time.
+    pub fn add_keyframe(&mut self, id: usize, time: Float, transform: Transform) {
+        let track = self.tracks.entry(id).or_default();
+        let index = track.partition_point(|key| key.time < time);
+        let keyframe = Keyframe { time, transform };
+        match track.get_mut(index) {
+            Some(existing) if existing.time == time => *existing = keyframe,
+            _ => track.insert(index, keyframe),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    /// Time of the last keyframe, or 0 if there are none.
+    pub fn duration(&self) -> Float {
+        self.tracks
+            .values()
+            .filter_map(|track| track.last())
+            .map(|key| key.time)
+            .fold(0.0, Float::max)
+    }
+
+    /// Get the transform of every animated object at `time` in seconds, ordered by id.
+    /// Before the first and after the last keyframe, objects hold still at that keyframe.
+    pub fn sample(&self, time: Float) -> Vec<(usize, Transform)> {
+        self.tracks
+            .iter()
+            .filter_map(|(&id, track)| {
+                let next = track.partition_point(|key| key.time <= time);
+                let transform = match (next.checked_sub(1).map(|i| &track[i]), track.get(next)) {
+                    (Some(prev), Some(next)) => {
+                        let t = (time - prev.time) / (next.time - prev.time);
+                        prev.transform.interpolate(&next.transform, t)
+                    }
+                    (Some(key), None) | (None, Some(key)) => key.transform,
+                    (None, None) => return None,
+                };
+                Some((id, transform))
+            })
+            .collect()
+    }
+
+    /// Move the objects of `scene` to where they are at `time`, rebuilding the accelerator.
+    /// Ids past the end of `Scene::objects` are ignored.
+    pub fn apply(&self, scene: &mut Scene, time: Float) {
+        for (id, transform) in self.sample(time) {
+            if let Some(object) = scene.objects.get_mut(id) {
+                object.set_transform(transform);
+            }
+        }
+        match scene.accelerator {
+            Accelerator::None => {}
+            Accelerator::Grid(_) => scene.build_grid(),
+            Accelerator::Spheres(_) => {
+                if !scene.build_sphere_set() {
+                    scene.accelerator = Accelerator::None;
+                }
+            }
+        }
+    }
+}
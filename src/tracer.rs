@@ -1,12 +1,15 @@
 use std::{
     borrow::BorrowMut,
-    f64::consts::PI,
-    ops::{Deref, DerefMut},
-    sync::{Arc, Condvar, Mutex},
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    mem::size_of,
+    ops::{AddAssign, Deref, DerefMut},
+    sync::{Arc, Condvar, Mutex, RwLock},
     thread::{spawn, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use rand::{rngs::ThreadRng, Rng, RngCore};
+use rand::{rngs::StdRng, thread_rng, Rng, RngCore, SeedableRng};
 use sdl2::{
     pixels::Color,
     rect::Point,
@@ -18,19 +21,443 @@ use crate::scene::*;
 use crate::*;
 
 pub struct Tracer {
+    /// Maximum number of surface hits per path, regardless of what happens at them.
+    pub max_depth: u16,
+    /// Maximum number of reflections per path.
     pub max_reflect: u16,
+    /// Maximum number of refractions per path.
     pub max_refract: u16,
-    pub fov: f64,
+    pub fov: Float,
+    pub projection: Projection,
+    /// Radial lens distortion: positive bends straight lines outward like a wide-angle barrel,
+    /// negative pinches them inward like a telephoto pincushion, and 0 is a pinhole camera.
+    pub distortion: Float,
+    /// Radius of the camera lens in world units, or 0 for a pinhole camera where all is in focus.
+    pub aperture: Float,
+    /// Distance along the camera's Z axis that is in focus when `aperture` is nonzero.
+    pub focus_distance: Float,
+    pub bokeh_shape: BokehShape,
     pub reflect_samples: u16,
+    /// Most extra samples for a pixel whose path reflected, scaled by the roughest surface it
+    /// reflected off so that mirrors take none and diffuse surfaces take them all.
+    /// Replaces `reflect_samples` when nonzero.
+    pub glossy_samples: u16,
     pub refract_samples: u16,
+    /// Number of points to pick on the lights at each diffuse bounce, averaging their light.
+    /// More make soft shadows less noisy, at the cost of a shadow ray each.
+    pub light_samples: u16,
+    pub sample_combine: SampleCombine,
+    /// Paths stop once no color channel can contribute more than this.
+    pub min_contribution: Float,
+    /// Size in pixels of the squares traced by `trace_preview`.
+    pub preview_scale: u16,
+    pub sample_pattern: SamplePattern,
+    /// Seed each pixel's random numbers from its coordinates, the pass and this value,
+    /// so the image doesn't depend on thread count or scheduling.
+    /// An irradiance cache still makes it depend on the order pixels are traced in.
+    /// If `None`, the random number generator passed in is used for all pixels.
+    pub seed: Option<u64>,
+    pub pixel_filter: PixelFilter,
+    /// Cache of indirect diffuse lighting, reused at first-hit diffuse surfaces when present.
+    pub irradiance_cache: Option<IrradianceCache>,
+    /// Caustics to add at diffuse surfaces, from `build_photon_map`. Paths leaving those surfaces
+    /// then skip lights they reach through mirrors and clear objects, which the map already covers.
+    pub photon_map: Option<PhotonMap>,
+    /// Raise the roughness of each bounce to at least that of earlier bounces on the path,
+    /// which trades a little bias for much less noise from glossy surfaces lit by diffuse ones.
+    pub regularize: bool,
+    pub render_mode: RenderMode,
+    /// Give each path that refracts through a dispersive object a single wavelength,
+    /// so that the index of refraction can vary with it.
+    pub spectral: bool,
+    /// Trace all pixels of a pass together one bounce at a time, instead of one pixel at a time.
+    /// With a `seed`, this renders the same image.
+    pub wavefront: bool,
+    /// Number of times to trace pixels on a geometric edge, where the first hits of neighboring
+    /// pixels differ in object, normal or depth. Other pixels are traced once.
+    /// 1 or less disables edge detection, which costs five extra rays per pixel.
+    pub edge_samples: u16,
+    /// Per-pixel priority that pours extra samples into part of the image, so it converges first.
+    pub priority: Option<PriorityMap>,
+    /// Number of times to trace pixels of priority 1 each pass; lower priorities get
+    /// proportionally fewer, but at least one.
+    pub priority_samples: u16,
+}
+
+/// Approximate indirect diffuse lighting by averaging samples over cells of a spatial grid,
+/// and interpolating between the averages of neighboring cells.
+/// Each cell is further split by the dominant axis of the surface normal,
+/// so that opposite sides of a thin surface don't share lighting.
+pub struct IrradianceCache {
+    /// Size of a grid cell in world units.
+    pub cell_size: Float,
+    /// Number of samples a cell needs before it is used instead of tracing.
+    pub min_samples: u32,
+    // Split over several locks by key, so render threads rarely wait on each other.
+    shards: Vec<RwLock<HashMap<CacheKey, CacheCell>>>,
+}
+
+// Grid cell and side of the normal that a cache sample belongs to.
+type CacheKey = (i64, i64, i64, u8);
+// Sum of the irradiance samples in a cell, and how many there are.
+type CacheCell = (Vector<3>, u32);
+
+const CACHE_SHARDS: usize = 64;
+
+impl IrradianceCache {
+    pub fn new(cell_size: Float, min_samples: u32) -> IrradianceCache {
+        IrradianceCache {
+            cell_size,
+            min_samples,
+            shards: (0..CACHE_SHARDS)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    fn side(normal: Vector<3>) -> u8 {
+        let mut axis = 0;
+        for i in 1..3 {
+            if normal[i].abs() > normal[axis].abs() {
+                axis = i;
+            }
+        }
+        axis as u8 * 2 + (normal[axis] < 0.0) as u8
+    }
+
+    fn shard(&self, key: CacheKey) -> &RwLock<HashMap<CacheKey, CacheCell>> {
+        let hash = (key.0.wrapping_mul(73856093))
+            ^ (key.1.wrapping_mul(19349663))
+            ^ (key.2.wrapping_mul(83492791))
+            ^ key.3 as i64;
+        &self.shards[hash.rem_euclid(self.shards.len() as i64) as usize]
+    }
+
+    // Average of a cell, if it has enough samples.
+    fn mean(&self, key: CacheKey) -> Option<Vector<3>> {
+        match self.shard(key).read().unwrap().get(&key) {
+            Some((sum, count)) if *count >= self.min_samples => Some(*sum / *count),
+            _ => None,
+        }
+    }
+
+    /// Get the irradiance near a point, if enough samples have been gathered in its cell.
+    /// Blends trilinearly between the centers of the surrounding cells that have enough samples.
+    pub fn lookup(&self, pos: Vector<3>, normal: Vector<3>) -> Option<Vector<3>> {
+        let side = IrradianceCache::side(normal);
+        let grid = pos / self.cell_size;
+        let cell = grid.data().map(|f| f.floor() as i64);
+        self.mean((cell[0], cell[1], cell[2], side))?;
+        // Position relative to the center of the cell below it on each axis.
+        let offset = grid - vector![0.5, 0.5, 0.5];
+        let base = offset.data().map(|f| f.floor() as i64);
+        let mut sum = vector![0, 0, 0];
+        let mut total = 0.0;
+        for corner in 0..8 {
+            let mut neighbor = base;
+            let mut weight = 1.0;
+            for axis in 0..3 {
+                let t = offset[axis] - base[axis] as Float;
+                if corner & (1 << axis) != 0 {
+                    neighbor[axis] += 1;
+                    weight *= t;
+                } else {
+                    weight *= 1.0 - t;
+                }
+            }
+            if let Some(mean) = self.mean((neighbor[0], neighbor[1], neighbor[2], side)) {
+                sum += mean * weight;
+                total += weight;
+            }
+        }
+        // Cells without enough samples are left out, and the point's own cell is never one.
+        Some(sum / total)
+    }
+
+    /// Add an irradiance sample at a point.
+    pub fn insert(&self, pos: Vector<3>, normal: Vector<3>, irradiance: Vector<3>) {
+        let cell = (pos / self.cell_size).data().map(|f| f.floor() as i64);
+        let key = (cell[0], cell[1], cell[2], IrradianceCache::side(normal));
+        let mut cells = self.shard(key).write().unwrap();
+        let cell = cells.entry(key).or_insert((vector![0, 0, 0], 0));
+        cell.0 += irradiance;
+        cell.1 += 1;
+    }
+
+    /// Forget all samples, e.g. after the scene changed.
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.write().unwrap().clear();
+        }
+    }
+}
+
+/// Light that reached diffuse surfaces through mirrors and clear objects, traced from the lights
+/// because paths from the camera rarely find its way back, e.g. the caustic under a glass sphere.
+pub struct PhotonMap {
+    /// Distance in world units within which photons light a point.
+    pub radius: Float,
+    cells: HashMap<(i64, i64, i64), Vec<Photon>>,
+}
+
+#[derive(Clone, Copy)]
+struct Photon {
+    pos: Vector<3>,
+    // Direction the photon came from.
+    dir: Vector<3>,
+    power: Vector<3>,
+}
+
+impl PhotonMap {
+    pub fn new(radius: Float) -> PhotonMap {
+        PhotonMap {
+            radius,
+            cells: HashMap::new(),
+        }
+    }
+
+    fn key(&self, pos: Vector<3>) -> (i64, i64, i64) {
+        let cell = (pos / self.radius).data().map(|f| f.floor() as i64);
+        (cell[0], cell[1], cell[2])
+    }
+
+    /// Number of photons stored.
+    pub fn len(&self) -> usize {
+        self.cells.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    // Store a photon of `power` that arrived at `pos` from `dir`.
+    fn insert(&mut self, pos: Vector<3>, dir: Vector<3>, power: Vector<3>) {
+        let photon = Photon { pos, dir, power };
+        self.cells.entry(self.key(pos)).or_default().push(photon);
+    }
+
+    /// Get the light reflected off a white diffuse surface at `pos` by the photons within `radius`.
+    pub fn radiance(&self, pos: Vector<3>, normal: Vector<3>) -> Vector<3> {
+        let (x, y, z) = self.key(pos);
+        let mut sum = vector![0, 0, 0];
+        // The cells are as big as the radius, so the photons are in this one or its neighbors.
+        let cells = (0..27).map(|i| (x + i % 3 - 1, y + i / 3 % 3 - 1, z + i / 9 - 1));
+        for cell in cells {
+            for photon in self.cells.get(&cell).into_iter().flatten() {
+                let cos_theta = normal.dot(photon.dir);
+                if cos_theta <= 0.0
+                    || (photon.pos - pos).sqr_magnitude() > self.radius * self.radius
+                {
+                    continue;
+                }
+                // Reflect it the way a diffuse bounce does; see `diffuse_pdf`.
+                sum += photon.power * (diffuse_pdf(normal, photon.dir) / cos_theta);
+            }
+        }
+        sum / (consts::PI * self.radius * self.radius)
+    }
+}
+
+/// Weights from 0 to 1 over a grid, stretched to cover whatever size of image is traced.
+#[derive(Clone)]
+pub struct PriorityMap {
+    width: u16,
+    height: u16,
+    weights: Vec<Float>,
+}
+
+impl PriorityMap {
+    /// Create a map from `width` by `height` weights in row-major order.
+    pub fn new(width: u16, height: u16, weights: Vec<Float>) -> PriorityMap {
+        assert!(width > 0 && height > 0);
+        assert_eq!(weights.len(), width as usize * height as usize);
+        PriorityMap {
+            width,
+            height,
+            weights,
+        }
+    }
+
+    /// Create a map that is 1 at `center` and falls off smoothly to 0 at `radius` away,
+    /// both in fractions of the image height, with 0 at the top left corner.
+    /// A center of (`aspect / 2`, 0.5) focuses on the middle of the screen.
+    pub fn radial(width: u16, height: u16, center: (Float, Float), radius: Float) -> PriorityMap {
+        let mut weights = Vec::with_capacity(width as usize * height as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let dx = (x as Float + 0.5) / height as Float - center.0;
+                let dy = (y as Float + 0.5) / height as Float - center.1;
+                let t = (1.0 - (dx * dx + dy * dy).sqrt() / radius).clamp(0.0, 1.0);
+                weights.push(t * t * (3.0 - 2.0 * t));
+            }
+        }
+        PriorityMap::new(width, height, weights)
+    }
+
+    /// Get the weight at pixel `x`, `y` of a `width` by `height` image.
+    pub fn get(&self, x: u16, y: u16, width: u16, height: u16) -> Float {
+        let map_x = x as usize * self.width as usize / width.max(1) as usize;
+        let map_y = y as usize * self.height as usize / height.max(1) as usize;
+        self.weights[map_y.min(self.height as usize - 1) * self.width as usize
+            + map_x.min(self.width as usize - 1)]
+    }
+}
+
+/// Distribution of sub-pixel sample positions across passes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SamplePattern {
+    /// Independent uniform random offsets.
+    White,
+    /// Per-pixel blue-noise-like offsets that cycle every pass.
+    Blue,
+    /// Jittered offsets within a 4x4 grid of cells, cycling through the cells every pass.
+    Stratified,
+    /// Low-discrepancy Halton sequence in bases 2 and 3, indexed by pass.
+    /// Each pixel shifts the sequence by its own random offset so neighbors don't line up.
+    Halton,
+}
+
+/// Reconstruction filter that weighs samples by their distance from the pixel center.
+/// Implemented by spreading sample positions according to the filter, so every sample keeps
+/// the same weight and the framebuffers can keep averaging.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PixelFilter {
+    /// Samples are spread evenly over the pixel.
+    Box,
+    /// Samples fall off linearly up to one pixel away from the center.
+    Tent,
+    /// Samples are normally distributed with a standard deviation of `radius` pixels.
+    Gaussian { radius: Float },
+}
+
+impl PixelFilter {
+    // Map an offset uniform from -0.5 to 0.5 on both axes to one distributed like the filter.
+    fn warp(&self, x: Float, y: Float) -> (Float, Float) {
+        let tent = |t: Float| {
+            let t = t + 0.5;
+            if t < 0.5 {
+                (2.0 * t).sqrt() - 1.0
+            } else {
+                1.0 - (2.0 - 2.0 * t).sqrt()
+            }
+        };
+        match self {
+            PixelFilter::Box => (x, y),
+            PixelFilter::Tent => (tent(x), tent(y)),
+            PixelFilter::Gaussian { radius } => {
+                // Box-Muller transform. Single precision can round x up to 0.5.
+                let distance = *radius * (-2.0 * (0.5 - x).max(Float::MIN_POSITIVE).ln()).sqrt();
+                let angle = consts::TAU * (y + 0.5);
+                (distance * angle.cos(), distance * angle.sin())
+            }
+        }
+    }
+}
+
+/// Which pixels of an image `Tracer::trace_partial_image` traces, and for which pass.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ImagePart {
+    /// Trace every pixel whose numeric index, counting along rows, leaves a remainder of
+    /// `interlace_offset` when divided by `interlace_amount`.
+    pub interlace_amount: u16,
+    pub interlace_offset: u16,
+    /// X, Y, width, height rectangle within the framebuffer.
+    pub bounds: (u16, u16, u16, u16),
+    /// Index of the pass, used to vary the sample pattern between passes.
+    pub frame: u32,
+}
+
+impl ImagePart {
+    /// Every pixel of a `width` by `height` image.
+    pub fn whole(width: u16, height: u16, frame: u32) -> ImagePart {
+        ImagePart {
+            interlace_amount: 1,
+            interlace_offset: 0,
+            bounds: (0, 0, width, height),
+            frame,
+        }
+    }
+}
+
+/// How the camera maps pixels to ray directions.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Projection {
+    /// Flat image plane spanning `fov` horizontally.
+    Perspective,
+    /// Full 360 degree panorama, with longitude along X and latitude along Y, looking forward
+    /// in the middle. Meant for images twice as wide as they are tall. Ignores `fov`,
+    /// `distortion` and `aperture`, as depth of field needs a flat focus plane.
+    Equirectangular,
+}
+
+/// Shape of the camera lens, which out-of-focus highlights take on.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BokehShape {
+    /// Round lens.
+    Disk,
+    /// Regular polygon with a corner for each aperture blade, such as 5 or 6.
+    Polygon { blades: u8 },
+}
+
+impl BokehShape {
+    /// Get a uniformly distributed point on the lens, with a radius of 1.
+    pub fn sample(&self, rng: &mut dyn RngCore) -> (Float, Float) {
+        match *self {
+            BokehShape::Disk => {
+                let point = sample_disk(rng);
+                (point[0], point[1])
+            }
+            BokehShape::Polygon { blades } => {
+                // Pick one of the triangles between the center and an edge, then a point in it.
+                let blades = blades.max(3) as Float;
+                let side = (rng.gen::<Float>() * blades).floor();
+                let angle0 = consts::TAU * side / blades;
+                let angle1 = consts::TAU * (side + 1.0) / blades;
+                let radius = rng.gen::<Float>().sqrt();
+                let t = rng.gen::<Float>();
+                (
+                    radius * (angle0.cos() * (1.0 - t) + angle1.cos() * t),
+                    radius * (angle0.sin() * (1.0 - t) + angle1.sin() * t),
+                )
+            }
+        }
+    }
+}
+
+/// What `trace_single_ray` computes for each ray.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    /// Fully lit path tracing.
+    Beauty,
+    /// Unlit color of the first surface hit, or the sky on a miss, without any bounces.
+    Albedo,
+    /// Number of objects the camera ray was tested against, from blue for none
+    /// through green and yellow to red for `max_tests` or more.
+    HeatMap { max_tests: u64 },
+    /// Path tracing inside a white furnace: the sky is 1 in every direction and every surface
+    /// is white and non-emissive, so an energy-conserving tracer renders an all-white image.
+    /// Darker areas lose energy and brighter ones create it; see `furnace_average`.
+    Furnace,
+    /// White where the camera ray hits a triangle within `width` of an edge, measured as the
+    /// smallest barycentric coordinate, and black everywhere else. For checking mesh topology.
+    Wireframe { width: Float },
+}
+
+/// How multiple samples of a single ray are combined.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SampleCombine {
+    /// Average of all samples.
+    Mean,
+    /// Per-channel median of an odd number of samples, which rejects outliers.
+    Median,
 }
 
 pub fn rgba_to_vector(color: Color) -> Vector<4> {
     vector![color.r, color.g, color.b, color.a] / 255.0
 }
 
-pub fn vector_to_rgba(mut vector: Vector<4>) -> Color {
-    vector *= 255;
+pub fn vector_to_rgba(vector: Vector<4>) -> Color {
+    let vector = vector.saturate() * 255;
     Color {
         r: vector[0] as u8,
         g: vector[1] as u8,
@@ -43,8 +470,8 @@ pub fn rgb_to_vector(color: Color) -> Vector<3> {
     vector![color.r, color.g, color.b] / 255.0
 }
 
-pub fn vector_to_rgb(mut vector: Vector<3>) -> Color {
-    vector *= 255;
+pub fn vector_to_rgb(vector: Vector<3>) -> Color {
+    let vector = vector.saturate() * 255;
     Color {
         r: vector[0] as u8,
         g: vector[1] as u8,
@@ -53,140 +480,1065 @@ pub fn vector_to_rgb(mut vector: Vector<3>) -> Color {
     }
 }
 
-impl Tracer {
-    pub fn default() -> Tracer {
+/// Hash a seed, pixel coordinates and pass index into the seed for that pixel's random numbers.
+fn pixel_seed(seed: u64, x: u16, y: u16, frame: u32) -> u64 {
+    let mut h = seed ^ 0x9e3779b97f4a7c15;
+    for i in [x as u64, y as u64, frame as u64] {
+        h ^= i;
+        h = h.wrapping_mul(0xbf58476d1ce4e5b9);
+        h ^= h >> 31;
+    }
+    h
+}
+
+// Blend `base` towards `color` for directions within the dot product threshold `radius`
+// of `center`, fully reaching it straight at the center.
+fn sky_disk(
+    base: Vector<3>,
+    dir: Vector<3>,
+    center: Vector<3>,
+    radius: Float,
+    color: Vector<3>,
+) -> Vector<3> {
+    let dot = dir.dot(center);
+    if dot >= radius {
+        let coeff = (dot - radius) / (1.0 - radius);
+        base + (color - base) * coeff
+    } else {
+        base
+    }
+}
+
+// Mirror the digits of `index` in `base` around the decimal point, which gives element `index`
+// of the van der Corput sequence in that base.
+fn radical_inverse(base: u32, mut index: u32) -> Float {
+    let mut result = 0.0;
+    let mut digit_scale = 1.0 / base as Float;
+    while index > 0 {
+        result += (index % base) as Float * digit_scale;
+        index /= base;
+        digit_scale /= base as Float;
+    }
+    result
+}
+
+/// Get the number of threads to use: `num_threads` if it is nonzero,
+/// otherwise the parallelism available to this process, falling back to 1.
+pub fn resolve_thread_count(num_threads: u16) -> u16 {
+    if num_threads != 0 {
+        return num_threads;
+    }
+    std::thread::available_parallelism().map_or(1, |n| n.get().min(u16::MAX as usize) as u16)
+}
+
+/// Get the index of refraction at a wavelength in micrometers by Cauchy's equation,
+/// where `ior` is the index at the sodium D line and `dispersion` is the B coefficient.
+pub fn cauchy_ior(ior: Float, dispersion: Float, wavelength: Float) -> Float {
+    ior + dispersion * (1.0 / (wavelength * wavelength) - 1.0 / (0.5893 * 0.5893))
+}
+
+/// Get how much light of a wavelength in micrometers contributes to each color channel.
+/// Averages to 1 in every channel over the visible range of 0.38 to 0.72.
+pub fn wavelength_to_rgb(wavelength: Float) -> Vector<3> {
+    let lobe = |center: Float, width: Float| (-((wavelength - center) / width).powi(2)).exp();
+    vector![
+        (lobe(0.600, 0.050) + 0.25 * lobe(0.430, 0.025)) * 3.412143,
+        lobe(0.545, 0.045) * 4.262766,
+        lobe(0.455, 0.035) * 5.487398
+    ]
+}
+
+// Neighboring pixels whose first hits have normals less alike than this dot product,
+// or distances differing by more than this fraction of the nearer one, are on an edge.
+const EDGE_MIN_NORMAL_DOT: Float = 0.9;
+const EDGE_MAX_DEPTH_RATIO: Float = 0.1;
+
+// Scale from the luminance of the Preetham sky model in kcd/m² to scene colors.
+const PREETHAM_EXPOSURE: Float = 0.1;
+
+/// Get the radiance of a clear sky in direction `dir` by Preetham's model,
+/// with the sun at `sun_dir` and `turbidity` describing the haze. Both are unit vectors,
+/// and `dir` should be above the horizon.
+pub fn preetham_sky(turbidity: Float, sun_dir: Vector<3>, dir: Vector<3>) -> Vector<3> {
+    let t = turbidity;
+    // Angles from the zenith, which is -Y, and between the direction and the sun.
+    let theta_sun = (-sun_dir[1]).clamp(-1.0, 1.0).acos().min(consts::PI * 0.5);
+    let cos_theta = (-dir[1]).max(0.001);
+    let gamma = dir.dot(sun_dir).clamp(-1.0, 1.0).acos();
+
+    // Perez et al.'s distribution, relative to its value at the zenith.
+    let perez = |[a, b, c, d, e]: [Float; 5]| {
+        let f = |cos_theta: Float, gamma: Float| {
+            (1.0 + a * (b / cos_theta).exp())
+                * (1.0 + c * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+        };
+        f(cos_theta, gamma) / f(1.0, theta_sun)
+    };
+    let lum = perez([
+        0.1787 * t - 1.4630,
+        -0.3554 * t + 0.4275,
+        -0.0227 * t + 5.3251,
+        0.1206 * t - 2.5771,
+        -0.0670 * t + 0.3703,
+    ]);
+    let chroma_x = perez([
+        -0.0193 * t - 0.2592,
+        -0.0665 * t + 0.0008,
+        -0.0004 * t + 0.2125,
+        -0.0641 * t - 0.8989,
+        -0.0033 * t + 0.0452,
+    ]);
+    let chroma_y = perez([
+        -0.0167 * t - 0.2608,
+        -0.0950 * t + 0.0092,
+        -0.0079 * t + 0.2102,
+        -0.0441 * t - 1.6537,
+        -0.0109 * t + 0.0529,
+    ]);
+
+    // Color at the zenith.
+    let chi = (4.0 / 9.0 - t / 120.0) * (consts::PI - 2.0 * theta_sun);
+    let zenith_lum = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+    let th = vector![theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1];
+    let tt = vector![t * t, t, 1];
+    let zenith_chroma = |m: [[Float; 4]; 3]| {
+        tt.dot(vector![
+            th.dot(Vector::new(m[0])),
+            th.dot(Vector::new(m[1])),
+            th.dot(Vector::new(m[2]))
+        ])
+    };
+    let zenith_x = zenith_chroma([
+        [0.00166, -0.00375, 0.00209, 0.0],
+        [-0.02903, 0.06377, -0.03202, 0.00394],
+        [0.11693, -0.21196, 0.06052, 0.25886],
+    ]);
+    let zenith_y = zenith_chroma([
+        [0.00275, -0.00610, 0.00317, 0.0],
+        [-0.04214, 0.08970, -0.04153, 0.00516],
+        [0.15346, -0.26756, 0.06670, 0.26688],
+    ]);
+
+    // Convert from xyY to linear sRGB.
+    let lum = (zenith_lum * lum).max(0.0) * PREETHAM_EXPOSURE;
+    let x = zenith_x * chroma_x;
+    let y = zenith_y * chroma_y;
+    let xyz = vector![x / y * lum, lum, (1.0 - x - y) / y * lum];
+    vector![
+        xyz.dot(vector![3.2406, -1.5372, -0.4986]),
+        xyz.dot(vector![-0.9689, 1.8758, 0.0415]),
+        xyz.dot(vector![0.0557, -0.2040, 1.0570])
+    ]
+    .max(vector![0, 0, 0])
+}
+
+/// Get the fraction of sunlight in each color channel that makes it through the atmosphere
+/// with the sun at `sun_dir`, by Rayleigh and aerosol scattering as in Preetham's model.
+pub fn preetham_sun_transmittance(turbidity: Float, sun_dir: Vector<3>) -> Vector<3> {
+    if sun_dir[1] >= 0.0 {
+        return vector![0, 0, 0];
+    }
+    // Relative optical mass of the air along the path to the sun, by Kasten's formula.
+    let theta = (-sun_dir[1]).clamp(-1.0, 1.0).acos();
+    let mass = 1.0 / (theta.cos() + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+    let beta = 0.04608365822050 * turbidity - 0.04586025928522;
+    // Representative wavelengths of the color channels, in micrometers.
+    Vector::new([0.65 as Float, 0.57, 0.475].map(|wavelength| {
+        let rayleigh = 0.008735 * wavelength.powf(-4.08);
+        let aerosol = beta * wavelength.powf(-1.3);
+        (-(rayleigh + aerosol) * mass).exp()
+    }))
+}
+
+// Map 0 to 1 onto a blue, cyan, green, yellow and red color ramp.
+fn heat_ramp(value: Float) -> Vector<3> {
+    const STOPS: [[Float; 3]; 5] = [
+        [0.0, 0.0, 1.0],
+        [0.0, 1.0, 1.0],
+        [0.0, 1.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [1.0, 0.0, 0.0],
+    ];
+    let pos = value.clamp(0.0, 1.0) * (STOPS.len() - 1) as Float;
+    let index = (pos as usize).min(STOPS.len() - 2);
+    let frac = pos - index as Float;
+    Vector::new(STOPS[index]) * (1.0 - frac) + Vector::new(STOPS[index + 1]) * frac
+}
+
+// Get the probability density of a diffuse bounce off a surface with `normal` going towards
+// `dir`. The bounce direction is a hemisphere vector plus the normal, which is spread as
+// 2cos(θ)/π within 45 degrees of the normal.
+fn diffuse_pdf(normal: Vector<3>, dir: Vector<3>) -> Float {
+    let cos_theta = normal.dot(dir);
+    if cos_theta >= consts::FRAC_1_SQRT_2 {
+        2.0 * cos_theta / consts::PI
+    } else {
+        0.0
+    }
+}
+
+// Whether a surface only mirrors or refracts light without scattering it,
+// so that the photon map carries light through it.
+fn is_specular(intersect: &Intersect) -> bool {
+    let prop = &intersect.prop;
+    !intersect.is_entry
+        || prop.opacity == 0.0
+        || (prop.roughness == 0.0 && prop.anisotropy.is_none())
+}
+
+// Weight of a sample from a strategy with probability density `pdf`
+// against one with `other_pdf`, by the power heuristic.
+fn power_heuristic(pdf: Float, other_pdf: Float) -> Float {
+    pdf * pdf / (pdf * pdf + other_pdf * other_pdf)
+}
+
+/// Schlick's approximation of the Fresnel reflectance at a surface,
+/// given the cosine of the angle of incidence and the reflectance `f0` at normal incidence.
+/// A standalone building block: `shade` still chooses between reflection and refraction
+/// by `PhysProp::opacity` alone.
+pub fn fresnel_schlick(cos_theta: Float, f0: Vector<3>) -> Vector<3> {
+    let factor = (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5);
+    f0 + (vector![1, 1, 1] - f0) * factor
+}
+
+/// Encode 8-bit RGB pixels, or RGBA pixels if `alpha` is set, as a PNG image
+/// using uncompressed deflate blocks.
+fn encode_png(width: u16, height: u16, alpha: bool, pixels: &[u8]) -> Vec<u8> {
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for byte in data {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xedb88320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+    fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = out.len();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(data);
+        let crc = crc32(&out[start..]);
+        out.extend_from_slice(&crc.to_be_bytes());
+    }
+
+    // Every scanline starts with filter type 0 (none).
+    let stride = width as usize * if alpha { 4 } else { 3 };
+    let mut raw = Vec::with_capacity((stride + 1) * height as usize);
+    for row in pixels.chunks(stride) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // Zlib stream made of stored blocks.
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(0xffff).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push((i == blocks.len() - 1) as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in &raw {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    zlib.extend_from_slice(&((b << 16) | a).to_be_bytes());
+
+    let mut ihdr = vec![];
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, if alpha { 6 } else { 2 }, 0, 0, 0]);
+
+    let mut out = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+    chunk(&mut out, b"IHDR", &ihdr);
+    chunk(&mut out, b"IDAT", &zlib);
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+impl Default for Tracer {
+    fn default() -> Tracer {
         Tracer {
+            max_depth: 8,
             max_reflect: 8,
             max_refract: 8,
             fov: 90.0,
+            projection: Projection::Perspective,
+            distortion: 0.0,
+            aperture: 0.0,
+            focus_distance: 1.0,
+            bokeh_shape: BokehShape::Disk,
             reflect_samples: 4,
+            glossy_samples: 0,
             refract_samples: 4,
+            light_samples: 1,
+            sample_combine: SampleCombine::Mean,
+            min_contribution: 0.0001,
+            preview_scale: 1,
+            sample_pattern: SamplePattern::White,
+            seed: Some(0),
+            pixel_filter: PixelFilter::Box,
+            irradiance_cache: None,
+            photon_map: None,
+            regularize: false,
+            render_mode: RenderMode::Beauty,
+            spectral: false,
+            wavefront: false,
+            edge_samples: 1,
+            priority: None,
+            priority_samples: 4,
         }
     }
+}
 
-    /// Get the closest intersection with a ray, if any.
-    pub fn get_intersection(&self, scene: &Scene, ray: Ray) -> Option<Intersect> {
-        let mut out: Option<Intersect> = None;
-        for i in 0..scene.objects.len() {
-            if let Some(intersect) = scene.objects[i].intersect(&ray) {
-                if let Some(cur) = out {
-                    if cur.distance > intersect.distance {
-                        out = Some(intersect);
-                    }
-                } else {
-                    out = Some(intersect);
-                }
-            }
-        }
-        out
+impl Tracer {
+    /// Get the closest intersection with a ray, if any, skipping objects hidden from `kind` of ray.
+    pub fn get_intersection(&self, scene: &Scene, ray: Ray, kind: RayKind) -> Option<Intersect> {
+        scene.raycast_visible(ray, kind)
+    }
+
+    // Like `get_intersection`, adding the objects tested to `stats`.
+    fn get_intersection_counted(
+        &self,
+        scene: &Scene,
+        ray: Ray,
+        kind: RayKind,
+        stats: &mut RenderStats,
+    ) -> Option<Intersect> {
+        let (hit, tests) = scene.raycast_visible_counted(ray, kind);
+        stats.intersection_tests += tests;
+        hit
     }
 
     /// Perform a single sample of ray tracing.
     pub fn trace_single_ray(
         &self,
         scene: &Scene,
-        mut ray: Ray,
-        rng: &mut ThreadRng,
+        ray: Ray,
+        rng: &mut dyn RngCore,
     ) -> RayTraceResult {
-        let mut result = RayTraceResult {
-            color: vector![0, 0, 0],
-            did_reflect: false,
-            did_refract: false,
-        };
-        let mut color_mask = vector![1, 1, 1];
-        let mut reflect = self.max_reflect;
+        self.trace_path(
+            scene,
+            ray,
+            RayKind::Camera,
+            rng,
+            self.irradiance_cache.as_ref(),
+        )
+    }
+
+    // Get the environment map to sample, which the furnace replaces with a uniform sky.
+    fn environment<'a>(&self, scene: &'a Scene) -> Option<&'a EnvMap> {
+        match self.render_mode {
+            RenderMode::Furnace => None,
+            _ => scene.environment.as_ref(),
+        }
+    }
+
+    // Get the lights to sample, of which the furnace has none.
+    fn lights<'a>(&self, scene: &'a Scene) -> &'a [usize] {
+        match self.render_mode {
+            RenderMode::Furnace => &[],
+            _ => &scene.lights,
+        }
+    }
+
+    // Get the photon map to gather caustics from, of which the furnace has none.
+    fn photon_map(&self) -> Option<&PhotonMap> {
+        match self.render_mode {
+            RenderMode::Furnace => None,
+            _ => self.photon_map.as_ref(),
+        }
+    }
+
+    /// Trace `photons` photons from random points on the lights and store those that reach a
+    /// diffuse surface through at least one mirror or clear object, for `Tracer::photon_map`.
+    pub fn build_photon_map(
+        &self,
+        scene: &Scene,
+        photons: u32,
+        radius: Float,
+        rng: &mut dyn RngCore,
+    ) -> PhotonMap {
+        let mut map = PhotonMap::new(radius);
+        let lights = self.lights(scene);
+        if lights.is_empty() {
+            return map;
+        }
+        // Dividing by the probability of each light, point and direction.
+        let scale = lights.len() as Float * 4.0 * consts::PI / photons as Float;
+        for _ in 0..photons {
+            let light = lights[rng.gen_range(0..lights.len())];
+            let object = &scene.objects[light];
+            let Some(point) = object.sample_point(rng) else {
+                continue;
+            };
+            let dir = Vector::<3>::random_unit_vector(rng);
+            // Find the surface there by casting back onto the point from just off it;
+            // from inside a closed light, the direction points into it.
+            let (min, max) = object.bounding_box();
+            let back = Ray {
+                pos: point + dir * ((max - min).magnitude() * 0.001),
+                normal: -dir,
+            };
+            let Some(mut surface) = object.intersect(&back).filter(|hit| hit.is_entry) else {
+                continue;
+            };
+            surface.object_id = light;
+            let cos_theta = surface.normal.dot(dir);
+            let power = scene.emission(&surface) * (cos_theta * object.surface_area() * scale);
+            self.trace_photon(
+                scene,
+                &mut map,
+                Ray {
+                    pos: point,
+                    normal: dir,
+                },
+                power,
+                rng,
+            );
+        }
+        map
+    }
+
+    // Follow a photon of `power` through mirrors and clear objects, storing it where it lands on
+    // a diffuse surface after at least one of them.
+    fn trace_photon(
+        &self,
+        scene: &Scene,
+        map: &mut PhotonMap,
+        ray: Ray,
+        power: Vector<3>,
+        rng: &mut dyn RngCore,
+    ) {
+        let mut path = PathState::new(self, ray, RayKind::Reflection);
+        let mut through_specular = false;
         loop {
-            if let Some(intersect) = self.get_intersection(scene, ray) {
-                // Ray hit an object; decide what to do next.
-                result.color += color_mask * intersect.prop.emission;
-                color_mask *= intersect.prop.color;
-
-                // Limit bounce count.
-                reflect -= 1;
-                result.did_reflect = true;
-                if reflect == 0 {
-                    return result;
+            let stats = &mut path.result.stats;
+            let Some(hit) = self.get_intersection_counted(scene, path.ray, path.kind, stats) else {
+                return;
+            };
+            if !is_specular(&hit) {
+                let is_diffuse = hit.prop.roughness == 1.0 && hit.prop.anisotropy.is_none();
+                if through_specular && is_diffuse {
+                    map.insert(hit.pos, -path.ray.normal, path.color_mask.hadamard(power));
                 }
+                return;
+            }
+            through_specular = true;
+            if !self.shade(scene, &mut path, Some(hit), rng, None) {
+                return;
+            }
+        }
+    }
+
+    // Get the probability density of `sample_light` picking the point `hit` on a light, per unit
+    // solid angle seen along `dir`. Picking by area is converted to solid angle through the
+    // geometry term: light spreads with the distance squared and the cosine at the light.
+    fn light_pdf(&self, scene: &Scene, hit: &Intersect, dir: Vector<3>) -> Float {
+        let cos_theta = hit.normal.dot(dir).abs();
+        let area = scene.objects[hit.object_id].surface_area();
+        if cos_theta <= 0.0 || area <= 0.0 {
+            return 0.0;
+        }
+        let lights = self.lights(scene).len() as Float;
+        hit.distance * hit.distance / (cos_theta * area * lights)
+    }
+
+    // Sample a random point on a random light from a diffuse surface, weighed against finding
+    // it by bouncing with multiple importance sampling, and by `share` of the bounce's samples.
+    fn sample_light(
+        &self,
+        scene: &Scene,
+        path: &mut PathState,
+        intersect: &Intersect,
+        share: Float,
+        rng: &mut dyn RngCore,
+    ) {
+        let lights = self.lights(scene);
+        let light = lights[rng.gen_range(0..lights.len())];
+        let Some(point) = scene.objects[light].sample_point(rng) else {
+            return;
+        };
+        let distance = (point - intersect.pos).magnitude();
+        let dir = (point - intersect.pos) / distance;
+        let bounce_pdf = diffuse_pdf(intersect.normal, dir);
+        if bounce_pdf <= 0.0 {
+            return;
+        }
+        let shadow_ray = Ray {
+            pos: intersect.pos,
+            normal: dir,
+        };
+        let shadow = self.get_intersection_counted(
+            scene,
+            shadow_ray,
+            RayKind::Shadow,
+            &mut path.result.stats,
+        );
+        // Anything else in the way blocks the point, including the near side of the light.
+        let Some(hit) = shadow.filter(|hit| {
+            hit.object_id == light && (hit.distance - distance).abs() <= distance * 0.001
+        }) else {
+            return;
+        };
+        let light_pdf = self.light_pdf(scene, &hit, dir);
+        if light_pdf > 0.0 {
+            let weight = power_heuristic(light_pdf, bounce_pdf);
+            path.result.add_light(
+                path.color_mask * scene.emission(&hit) * (bounce_pdf / light_pdf * weight * share),
+                path.hits + 1,
+            );
+        }
+    }
 
-                // Choose between reflection and refraction.
-                let refract_rng = rng.gen::<f64>();
-                if !intersect.is_entry || refract_rng > intersect.prop.opacity {
-                    // Determine refraction angle.
-                    let (ior0, ior1, normal) = if intersect.is_entry {
-                        (1.0, intersect.prop.ior, -intersect.normal)
+    /// Get the color of the sky in a direction.
+    fn sky_color(&self, scene: &Scene, dir: Vector<3>) -> Vector<3> {
+        if self.render_mode == RenderMode::Furnace {
+            return vector![1, 1, 1];
+        }
+        if let Some(environment) = &scene.environment {
+            return environment.radiance(dir);
+        }
+        let mut sky = match scene.sky_model {
+            SkyModel::Black => return vector![0, 0, 0],
+            SkyModel::Preetham { turbidity } => {
+                let sky = if dir[1] < 0.0 {
+                    preetham_sky(turbidity, scene.sun_direction, dir)
+                } else {
+                    // Below the horizon, light the ground by the sky just above it.
+                    let horizon = vector![dir[0], 0, dir[2]];
+                    let horizon = if horizon.sqr_magnitude() > 0.0 {
+                        horizon.as_unit_vector()
                     } else {
-                        (intersect.prop.ior, 1.0, intersect.normal)
+                        vector![0, 0, 1]
                     };
-                    let ratio = ior0 / ior1;
-                    let dot = ray.normal.dot(normal);
-                    ray.pos = intersect.pos;
-                    ray.normal = ray.normal * ratio
-                        + normal * ((1.0 - ratio * ratio * (1.0 - dot * dot)).sqrt() - ratio * dot);
+                    preetham_sky(turbidity, scene.sun_direction, horizon) * scene.ground_color
+                };
+                if dir[1] < 0.0 {
+                    let sun = scene.sun_color
+                        * preetham_sun_transmittance(turbidity, scene.sun_direction);
+                    sky_disk(sky, dir, scene.sun_direction, scene.sun_radius, sun)
                 } else {
-                    // Determine reflection angle.
-                    let diff_normal =
-                        (Vector::<3>::random_hemisphere_vector(rng, intersect.normal)
-                            + intersect.normal)
-                            .as_unit_vector();
-                    let spec_normal = (ray.normal
-                        - intersect.normal * (2.0 * ray.normal.dot(intersect.normal)))
-                    .as_unit_vector();
-                    ray.pos = intersect.pos;
-                    ray.normal =
-                        spec_normal + (diff_normal - spec_normal) * intersect.prop.roughness;
+                    sky
                 }
-            } else {
-                // Ray did not hit anything, get sky color and finish.
-                let mut coeff = ray.normal[1] * 3.0;
+            }
+            SkyModel::Gradient => {
+                let mut coeff = dir[1] * 3.0;
                 coeff = coeff.clamp(-1.0, 1.0);
                 let base = if coeff >= 0.0 {
                     scene.horizon_color + (scene.ground_color - scene.horizon_color) * coeff
                 } else {
                     scene.horizon_color + (scene.skybox_color - scene.horizon_color) * -coeff
                 };
-                let sun_dot = ray.normal.dot(scene.sun_direction);
-                if sun_dot >= scene.sun_radius {
-                    let sun_coeff = (sun_dot - scene.sun_radius) / (1.0 - scene.sun_radius);
-                    result.color += color_mask * (base + (scene.sun_color - base) * sun_coeff);
+                sky_disk(
+                    base,
+                    dir,
+                    scene.sun_direction,
+                    scene.sun_radius,
+                    scene.sun_color,
+                )
+            }
+        };
+        for light in &scene.sky_lights {
+            sky = sky_disk(sky, dir, light.direction, light.radius, light.color);
+        }
+        sky
+    }
+
+    /// Trace a single path, using `cache` for indirect diffuse lighting at the first hit if present.
+    fn trace_path(
+        &self,
+        scene: &Scene,
+        ray: Ray,
+        kind: RayKind,
+        rng: &mut dyn RngCore,
+        cache: Option<&IrradianceCache>,
+    ) -> RayTraceResult {
+        let mut path = PathState::new(self, ray, kind);
+        let hit = self.get_intersection_counted(scene, path.ray, path.kind, &mut path.result.stats);
+        self.continue_path(scene, path, hit, rng, cache)
+    }
+
+    // Trace the rest of a path whose next surface `hit` was already found.
+    fn continue_path(
+        &self,
+        scene: &Scene,
+        mut path: PathState,
+        mut hit: Option<Intersect>,
+        rng: &mut dyn RngCore,
+        cache: Option<&IrradianceCache>,
+    ) -> RayTraceResult {
+        while self.shade(scene, &mut path, hit, rng, cache) {
+            hit = self.get_intersection_counted(scene, path.ray, path.kind, &mut path.result.stats);
+        }
+        self.finish_path(scene, path)
+    }
+
+    // Get the result of a finished path, blending camera paths towards the fog color
+    // by how much fog lies between the camera and the first surface hit.
+    fn finish_path(&self, scene: &Scene, path: PathState) -> RayTraceResult {
+        let mut result = path.result;
+        result.roughness = path.path_roughness;
+        let (Some(ray), Some(distance)) = (path.camera_ray, path.first_distance) else {
+            return result;
+        };
+        if scene.fog_density <= 0.0 || self.render_mode == RenderMode::Furnace {
+            return result;
+        }
+        // Integrate density * exp(-falloff * height) along the ray, where height is -Y.
+        let rate = scene.fog_falloff * ray.normal[1];
+        let depth = scene.fog_density
+            * (scene.fog_falloff * ray.pos[1]).exp()
+            * if rate.abs() < PARALLEL_EPSILON {
+                distance
+            } else {
+                ((rate * distance).exp() - 1.0) / rate
+            };
+        let amount = 1.0 - (-depth).exp();
+        result.color = result.color * (1.0 - amount) + scene.fog_color * amount;
+        for aov in [Aov::Direct, Aov::Indirect, Aov::Emission] {
+            result.aovs[aov as usize] *= 1.0 - amount;
+        }
+        // The fog adds light like an emissive object would.
+        result.aovs[Aov::Emission as usize] += scene.fog_color * amount;
+        result
+    }
+
+    /// Advance a path past the surface it hit, or finish it if `hit` is `None`.
+    /// Returns whether the path continues, in which case `path.ray` is the next ray to cast.
+    fn shade(
+        &self,
+        scene: &Scene,
+        path: &mut PathState,
+        hit: Option<Intersect>,
+        rng: &mut dyn RngCore,
+        cache: Option<&IrradianceCache>,
+    ) -> bool {
+        if self.render_mode == RenderMode::Albedo {
+            let color = match hit {
+                Some(intersect) => intersect.prop.color,
+                None => self.sky_color(scene, path.ray.normal),
+            };
+            path.result.color = color;
+            path.result.aovs[Aov::Emission as usize] = color;
+            return false;
+        }
+        if let RenderMode::HeatMap { max_tests } = self.render_mode {
+            let tests = path.result.stats.intersection_tests;
+            let color = heat_ramp(tests as Float / max_tests.max(1) as Float);
+            path.result.color = color;
+            path.result.aovs[Aov::Emission as usize] = color;
+            return false;
+        }
+        if let RenderMode::Wireframe { width } = self.render_mode {
+            let bary = hit.and_then(|intersect| intersect.bary);
+            let color = match bary {
+                Some(bary) if bary.min_component() < width => vector![1, 1, 1],
+                _ => vector![0, 0, 0],
+            };
+            path.result.color = color;
+            path.result.aovs[Aov::Emission as usize] = color;
+            return false;
+        }
+        if let Some(mut intersect) = hit {
+            if self.render_mode == RenderMode::Furnace {
+                intersect.prop.color = vector![1, 1, 1];
+                intersect.prop.emission = vector![0, 0, 0];
+                intersect.prop.emission_texture = None;
+                intersect.prop.absorption = vector![0, 0, 0];
+            }
+            path.result.stats.bounces += 1;
+            if path.first_distance.is_none() {
+                path.first_distance = Some(intersect.distance);
+            }
+            if intersect.prop.shadow_catcher {
+                if path.kind == RayKind::Camera {
+                    // Check for shadow with a diffuse bounce; if blocked, leave it black.
+                    let probe = Ray {
+                        pos: intersect.pos,
+                        normal: (Vector::<3>::random_hemisphere_vector(rng, intersect.normal)
+                            + intersect.normal)
+                            .as_unit_vector(),
+                    };
+                    let shadow = self.get_intersection_counted(
+                        scene,
+                        probe,
+                        RayKind::Shadow,
+                        &mut path.result.stats,
+                    );
+                    if shadow.is_some_and(|hit| !hit.prop.shadow_catcher) {
+                        path.result.aovs[Aov::Alpha as usize] = vector![1, 1, 1];
+                        path.result.aovs[Aov::Shadow as usize] = vector![1, 1, 1];
+                        return false;
+                    }
+                }
+                // Otherwise show whatever is behind it.
+                path.ray.pos = intersect.pos;
+                return true;
+            }
+
+            // Ray hit an object; decide what to do next.
+            let bounce_pdf = path.bounce_pdf.take();
+            if path.hits == 0 {
+                path.result.aovs[Aov::Alpha as usize] = vector![1, 1, 1];
+            }
+            if path.hits == 1 {
+                path.result.aovs[Aov::Shadow as usize] = vector![1, 1, 1];
+            }
+            let mut emission = scene.emission(&intersect);
+            if let Some(bounce_pdf) = bounce_pdf {
+                if self.lights(scene).contains(&intersect.object_id) {
+                    let light_pdf = self.light_pdf(scene, &intersect, path.ray.normal);
+                    emission *= power_heuristic(bounce_pdf, light_pdf);
+                }
+            }
+            if path.caustic == Some(true) && self.lights(scene).contains(&intersect.object_id) {
+                // Already added from the photon map.
+                emission = vector![0, 0, 0];
+            }
+            path.result.add_light(path.color_mask * emission, path.hits);
+            path.caustic = path
+                .caustic
+                .filter(|_| is_specular(&intersect))
+                .map(|_| true);
+            path.color_mask = path.color_mask.hadamard(intersect.prop.color);
+            if !intersect.is_entry {
+                // The ray traveled through the object's medium; apply Beer-Lambert absorption.
+                path.color_mask *= Vector::new(
+                    (intersect.prop.absorption * -intersect.distance)
+                        .data()
+                        .map(Float::exp),
+                );
+            }
+
+            // Limit bounce count and stop once further bounces can't be seen.
+            path.depth = path.depth.saturating_sub(1);
+            if path.depth == 0 || path.color_mask.max_component() < self.min_contribution {
+                return false;
+            }
+
+            // Choose between reflection and refraction.
+            let refract_rng = rng.gen::<Float>();
+            if !intersect.is_entry || refract_rng > intersect.prop.opacity {
+                if path.refract == 0 {
+                    return false;
+                }
+                path.refract -= 1;
+                path.result.did_refract = true;
+                path.kind = RayKind::Reflection;
+
+                // Pick a wavelength for the rest of the path, weighting its color to match.
+                if self.spectral && path.wavelength.is_none() && intersect.prop.dispersion != 0.0 {
+                    let sample = 0.38 + 0.34 * rng.gen::<Float>();
+                    path.color_mask *= wavelength_to_rgb(sample);
+                    path.wavelength = Some(sample);
+                }
+                let dispersed = |(ior, dispersion): (Float, Float)| match path.wavelength {
+                    Some(wavelength) => cauchy_ior(ior, dispersion, wavelength),
+                    None => ior,
+                };
+
+                // Determine refraction angle from the media on either side of the surface.
+                let medium = (intersect.prop.ior, intersect.prop.dispersion);
+                let exit_index = path.ior_stack.iter().rposition(|entry| *entry == medium);
+                let (ior0, ior1, normal) = if intersect.is_entry {
+                    let outside = path.ior_stack.last().copied().map_or(1.0, dispersed);
+                    (outside, dispersed(medium), -intersect.normal)
+                } else {
+                    let surrounding = (path.ior_stack.iter().enumerate().rev())
+                        .find(|(i, _)| Some(*i) != exit_index)
+                        .map_or(1.0, |(_, entry)| dispersed(*entry));
+                    (dispersed(medium), surrounding, intersect.normal)
+                };
+                let ratio = ior0 / ior1;
+                let dot = path.ray.normal.dot(normal);
+                let cos_sqr = 1.0 - ratio * ratio * (1.0 - dot * dot);
+                path.ray.pos = intersect.pos;
+                if cos_sqr < 0.0 {
+                    // Total internal reflection; the ray stays in the same medium.
+                    path.ray.normal -= normal * (2.0 * dot);
                 } else {
-                    result.color += color_mask * base;
+                    path.ray.normal =
+                        path.ray.normal * ratio + normal * (cos_sqr.sqrt() - ratio * dot);
+                    if intersect.is_entry {
+                        path.ior_stack.push(medium);
+                    } else if let Some(index) = exit_index {
+                        path.ior_stack.remove(index);
+                    }
                 }
-                return result;
-            };
+            } else {
+                if path.reflect == 0 {
+                    return false;
+                }
+                path.reflect -= 1;
+                path.result.did_reflect = true;
+
+                // Determine reflection angle.
+                let diff_normal = (Vector::<3>::random_hemisphere_vector(rng, intersect.normal)
+                    + intersect.normal)
+                    .as_unit_vector();
+                let is_diffuse =
+                    intersect.prop.roughness == 1.0 && intersect.prop.anisotropy.is_none();
+                path.kind = if is_diffuse {
+                    RayKind::Shadow
+                } else {
+                    RayKind::Reflection
+                };
+                if let Some(cache) = cache.filter(|_| path.hits == 0) {
+                    if is_diffuse {
+                        // Fully diffuse; reuse cached lighting or trace and record a sample.
+                        let irradiance = match cache.lookup(intersect.pos, intersect.normal) {
+                            Some(irradiance) => irradiance,
+                            None => {
+                                let sample = Ray {
+                                    pos: intersect.pos,
+                                    normal: diff_normal,
+                                };
+                                let sample = self.trace_path(scene, sample, path.kind, rng, None);
+                                path.result.stats += sample.stats;
+                                let irradiance = sample.color;
+                                cache.insert(intersect.pos, intersect.normal, irradiance);
+                                irradiance
+                            }
+                        };
+                        // The cache doesn't keep the direct and indirect parts apart.
+                        path.result.color += path.color_mask * irradiance;
+                        path.result.aovs[Aov::Indirect as usize] += path.color_mask * irradiance;
+                        return false;
+                    }
+                }
+                if is_diffuse {
+                    if let Some(environment) = self.environment(scene) {
+                        // Also sample the environment directly, weighed against finding it by
+                        // bouncing with multiple importance sampling.
+                        let (dir, radiance, light_pdf) = environment.sample(rng);
+                        let bounce_pdf = diffuse_pdf(intersect.normal, dir);
+                        if bounce_pdf > 0.0 && light_pdf > 0.0 {
+                            let shadow_ray = Ray {
+                                pos: intersect.pos,
+                                normal: dir,
+                            };
+                            let shadow = self.get_intersection_counted(
+                                scene,
+                                shadow_ray,
+                                RayKind::Shadow,
+                                &mut path.result.stats,
+                            );
+                            if shadow.is_none_or(|hit| hit.prop.shadow_catcher) {
+                                let weight = power_heuristic(light_pdf, bounce_pdf);
+                                path.result.add_light(
+                                    path.color_mask * radiance * (bounce_pdf / light_pdf * weight),
+                                    path.hits + 1,
+                                );
+                            }
+                        }
+                    }
+                    if !self.lights(scene).is_empty() {
+                        let samples = self.light_samples.max(1);
+                        for _ in 0..samples {
+                            let share = 1.0 / samples as Float;
+                            self.sample_light(scene, path, &intersect, share, rng);
+                        }
+                    }
+                    if let Some(photon_map) = self.photon_map() {
+                        let caustic = photon_map.radiance(intersect.pos, intersect.normal);
+                        path.result
+                            .add_light(path.color_mask * caustic, path.hits + 2);
+                        path.caustic = Some(false);
+                    }
+                    path.bounce_pdf = Some(diffuse_pdf(intersect.normal, diff_normal));
+                }
+                let spec_normal = (path.ray.normal
+                    - intersect.normal * (2.0 * path.ray.normal.dot(intersect.normal)))
+                .as_unit_vector();
+                // Both lobes reflect `prop.color`, so blending the directions only conserves
+                // energy if the result stays a unit vector; a shorter direction darkens the
+                // sky lookup and breaks the intersection math downstream.
+                let perturb = diff_normal - spec_normal;
+                let min_roughness = if self.regularize {
+                    path.path_roughness
+                } else {
+                    0.0
+                };
+                let offset = match intersect.prop.anisotropy {
+                    None => {
+                        let roughness = intersect.prop.roughness.max(min_roughness);
+                        path.path_roughness = path.path_roughness.max(roughness);
+                        perturb * roughness
+                    }
+                    Some(aniso) => {
+                        // Stretch the perturbation separately along each tangent axis.
+                        let normal = intersect.normal;
+                        let (tangent, bitangent) = normal.tangent_basis(aniso.tangent);
+                        let roughness_u = aniso.roughness_u.max(min_roughness);
+                        let roughness_v = aniso.roughness_v.max(min_roughness);
+                        path.path_roughness = path.path_roughness.max(roughness_u).max(roughness_v);
+                        tangent * (perturb.dot(tangent) * roughness_u)
+                            + bitangent * (perturb.dot(bitangent) * roughness_v)
+                            + normal * (perturb.dot(normal) * (roughness_u + roughness_v) * 0.5)
+                    }
+                };
+                path.ray.pos = intersect.pos;
+                path.ray.normal = (spec_normal + offset).as_unit_vector();
+            }
+            path.hits += 1;
+        } else {
+            // Ray did not hit anything, get sky color and finish.
+            let mut sky = self.sky_color(scene, path.ray.normal);
+            if let (Some(environment), Some(bounce_pdf)) =
+                (self.environment(scene), path.bounce_pdf)
+            {
+                sky *= power_heuristic(bounce_pdf, environment.pdf(path.ray.normal));
+            }
+            path.result.add_light(path.color_mask * sky, path.hits);
+            return false;
         }
+        true
     }
 
     /// Perform multiple samples of ray tracing.
-    pub fn trace_multi_ray(&self, scene: &Scene, ray: Ray, rng: &mut ThreadRng) -> RayTraceResult {
-        let mut tmp = self.trace_single_ray(scene, ray, rng);
-        let samples = tmp.did_reflect as u16 * self.reflect_samples
-            + tmp.did_refract as u16 * self.refract_samples;
-        for _ in 0..samples {
-            tmp.color += self.trace_single_ray(scene, ray, rng).color;
+    pub fn trace_multi_ray(
+        &self,
+        scene: &Scene,
+        ray: Ray,
+        rng: &mut dyn RngCore,
+    ) -> RayTraceResult {
+        // The camera ray always hits the same surface, so the extra samples reuse the first hit
+        // and only branch off from there; the intersection tests are counted once.
+        let cache = self.irradiance_cache.as_ref();
+        let mut path = PathState::new(self, ray, RayKind::Camera);
+        let hit =
+            self.get_intersection_counted(scene, ray, RayKind::Camera, &mut path.result.stats);
+        let first = self.continue_path(scene, path, hit, rng, cache);
+        let samples = (0..self.extra_samples(&first))
+            .map(|_| {
+                let path = PathState::new(self, ray, RayKind::Camera);
+                self.continue_path(scene, path, hit, rng, cache)
+            })
+            .collect();
+        self.combine_samples(first, samples)
+    }
+
+    /// Trace a path for every ray in lockstep, one surface hit at a time:
+    /// all active rays are intersected, then all hits are shaded and finished paths dropped.
+    /// Each ray uses its own random number generator from `rngs`, so the results are the same
+    /// as calling `trace_single_ray` on every ray in turn.
+    pub fn trace_wavefront(
+        &self,
+        scene: &Scene,
+        rays: &[Ray],
+        rngs: &mut [StdRng],
+    ) -> Vec<RayTraceResult> {
+        let cache = self.irradiance_cache.as_ref();
+        let mut paths: Vec<PathState> = (rays.iter())
+            .map(|ray| PathState::new(self, *ray, RayKind::Camera))
+            .collect();
+        let mut active: Vec<usize> = (0..paths.len()).collect();
+        let mut hits = Vec::with_capacity(active.len());
+        while !active.is_empty() {
+            for &i in &active {
+                let path = &mut paths[i];
+                let stats = &mut path.result.stats;
+                hits.push(self.get_intersection_counted(scene, path.ray, path.kind, stats));
+            }
+            let mut hits = hits.drain(..);
+            active.retain(|&i| {
+                let hit = hits.next().unwrap();
+                self.shade(scene, &mut paths[i], hit, &mut rngs[i], cache)
+            });
+        }
+        (paths.into_iter())
+            .map(|path| self.finish_path(scene, path))
+            .collect()
+    }
+
+    // Number of samples to add to the first one of a pixel, based on what its path did.
+    fn extra_samples(&self, first: &RayTraceResult) -> u16 {
+        let reflect_samples = if self.glossy_samples > 0 {
+            (first.roughness.clamp(0.0, 1.0) * self.glossy_samples as Float).ceil() as u16
+        } else {
+            self.reflect_samples
+        };
+        let samples = first.did_reflect as u16 * reflect_samples
+            + first.did_refract as u16 * self.refract_samples;
+        match self.sample_combine {
+            SampleCombine::Mean => samples,
+            // Use an odd total sample count so there is a single middle value.
+            SampleCombine::Median => (samples | 1) - 1,
+        }
+    }
+
+    // Combine the first sample of a pixel with the `extra_samples` after it.
+    fn combine_samples(
+        &self,
+        mut first: RayTraceResult,
+        samples: Vec<RayTraceResult>,
+    ) -> RayTraceResult {
+        let count = (samples.len() + 1) as Float;
+        for sample in &samples {
+            first.stats += sample.stats;
+        }
+        match self.sample_combine {
+            SampleCombine::Mean => {
+                for sample in &samples {
+                    first.color += sample.color;
+                    for i in 0..AOV_COUNT {
+                        first.aovs[i] += sample.aovs[i];
+                    }
+                }
+                first.color /= count;
+            }
+            SampleCombine::Median => {
+                // The render layers are still averaged.
+                let mut colors = vec![first.color];
+                for sample in &samples {
+                    colors.push(sample.color);
+                    for i in 0..AOV_COUNT {
+                        first.aovs[i] += sample.aovs[i];
+                    }
+                }
+                for i in 0..3 {
+                    let mut channel: Vec<Float> = colors.iter().map(|c| c[i]).collect();
+                    channel.sort_by(Float::total_cmp);
+                    first.color[i] = channel[channel.len() / 2];
+                }
+            }
+        }
+        for aov in &mut first.aovs {
+            *aov /= count;
         }
-        tmp.color /= (samples + 1) as f64;
-        tmp
+        first
     }
 
-    /// Ray-trace an image with multiple threads.
+    /// Ray-trace an image with multiple threads, or one per available core if `num_threads` is 0.
+    /// `frame` is the index of this pass, used to vary the sample pattern between passes.
     pub fn trace_image_async(
         self: &Arc<Self>,
         scene: Arc<Scene>,
         fb: &mut dyn Framebuffer,
         camera: &Transform,
         num_threads: u16,
-    ) {
-        let bounds = (0, 0, fb.width(), fb.height());
+        frame: u32,
+    ) -> RenderStats {
+        let start = Instant::now();
+        let num_threads = resolve_thread_count(num_threads);
+        let whole = ImagePart::whole(fb.width(), fb.height(), frame);
 
         let mut handles = vec![];
         let mut partial = vec![];
         for i in 0..num_threads {
-            let fb = Arc::new(Mutex::new(PartialFramebuffer::new(
-                fb.width(),
-                fb.height(),
-                num_threads,
-                i,
-            )));
+            let mut part = PartialFramebuffer::new(fb.width(), fb.height(), num_threads, i);
+            if fb.wants_aovs() {
+                part.enable_aovs();
+            }
+            let fb = Arc::new(Mutex::new(part));
             partial.push(fb.clone());
             let camera = *camera;
             let self2 = self.clone();
@@ -198,19 +1550,55 @@ impl Tracer {
                     fb.lock().unwrap().deref_mut(),
                     &camera,
                     &mut rng,
-                    num_threads,
-                    i,
-                    bounds,
-                );
+                    ImagePart {
+                        interlace_amount: num_threads,
+                        interlace_offset: i,
+                        ..whole
+                    },
+                )
             }));
         }
 
+        let mut stats = RenderStats::default();
         for handle in handles {
-            handle.join().unwrap();
+            stats += handle.join().unwrap();
         }
         for part in partial {
             part.lock().unwrap().update(fb);
         }
+        stats.wall_time = start.elapsed();
+        stats
+    }
+
+    /// Render a sequence of frames headlessly and save them as `frame_0000.png` and onwards in `dir`.
+    /// `frame_fn` returns the scene and camera for each frame index,
+    /// and each frame accumulates `samples` passes at a width, height of `size`.
+    pub fn render_animation<F: Fn(usize) -> (Scene, Transform)>(
+        self: &Arc<Self>,
+        frame_fn: F,
+        frame_count: usize,
+        size: (u16, u16),
+        samples: u16,
+        num_threads: u16,
+        dir: &str,
+    ) -> Result<(), String> {
+        for i in 0..frame_count {
+            let (scene, camera) = frame_fn(i);
+            let scene = Arc::new(scene);
+            let mut buffer = SmoothingFramebuffer::new(size.0, size.1);
+            for sample in 0..samples {
+                self.trace_image_async(
+                    scene.clone(),
+                    &mut buffer,
+                    &camera,
+                    num_threads,
+                    sample as u32,
+                );
+                buffer.frame += 1;
+            }
+            buffer.save_png(&format!("{}/frame_{:04}.png", dir, i))?;
+        }
+        Ok(())
     }
 
     /// Ray-trace an entire image.
@@ -219,67 +1607,670 @@ impl Tracer {
         scene: &Scene,
         fb: &mut dyn Framebuffer,
         camera: &Transform,
-        rng: &mut ThreadRng,
-    ) {
-        let bounds = (0, 0, fb.width(), fb.height());
-        self.trace_partial_image(scene, fb, camera, rng, 1, 0, bounds);
+        rng: &mut dyn RngCore,
+        frame: u32,
+    ) -> RenderStats {
+        let start = Instant::now();
+        let part = ImagePart::whole(fb.width(), fb.height(), frame);
+        let mut stats = self.trace_partial_image(scene, fb, camera, rng, part);
+        stats.wall_time = start.elapsed();
+        stats
     }
 
-    /// Ray-trace part of an image.
-    /// Pixels are interlaced by numeric index.
-    /// `bounds` describes an X, Y, width, height rectangle within the framebuffer.
+    /// Ray-trace the pixels of an image selected by `part`.
     pub fn trace_partial_image(
         &self,
         scene: &Scene,
         fb: &mut dyn Framebuffer,
         camera: &Transform,
-        rng: &mut ThreadRng,
-        interlace_amount: u16,
-        interlace_offset: u16,
-        bounds: (u16, u16, u16, u16),
+        rng: &mut dyn RngCore,
+        part: ImagePart,
+    ) -> RenderStats {
+        let width = fb.width();
+        let height = fb.height();
+        let ImagePart {
+            interlace_amount,
+            interlace_offset,
+            bounds,
+            frame,
+        } = part;
+        let pixels = (bounds.1..(bounds.1 + bounds.3))
+            .flat_map(|y| (bounds.0..(bounds.0 + bounds.2)).map(move |x| (x, y)))
+            .filter(|&(x, y)| {
+                (x as usize + y as usize * width as usize) % interlace_amount as usize
+                    == interlace_offset as usize
+            });
+        if self.wavefront {
+            return self.trace_pixels_wavefront(scene, fb, camera, rng, pixels.collect(), frame);
+        }
+        let mut stats = RenderStats::default();
+        for (x, y) in pixels {
+            let result = self.trace_pixel(scene, camera, (x, y), (width, height), rng, frame);
+            fb.set_pixel(x, y, result.color);
+            fb.set_aovs(x, y, &result.aovs);
+            stats += result.stats;
+        }
+        stats
+    }
+
+    /// Trace one pixel of a `size` image for pass `frame`, giving exactly what
+    /// `trace_partial_image` would write to it. Useful for inspecting or testing a single pixel.
+    pub fn trace_pixel(
+        &self,
+        scene: &Scene,
+        camera: &Transform,
+        (x, y): (u16, u16),
+        (width, height): (u16, u16),
+        rng: &mut dyn RngCore,
+        frame: u32,
+    ) -> RayTraceResult {
+        let count = self.pixel_sample_count(scene, camera, x, y, width, height);
+        let mut results = Vec::with_capacity(count as usize);
+        for i in 0..count {
+            // Give each sample of the pixel its own pass index for the sample pattern.
+            let frame = frame * count as u32 + i as u32;
+            let mut pixel_rng;
+            let rng: &mut dyn RngCore = match self.seed {
+                Some(seed) => {
+                    pixel_rng = StdRng::seed_from_u64(pixel_seed(seed, x, y, frame));
+                    &mut pixel_rng
+                }
+                None => &mut *rng,
+            };
+            let (rand_x, rand_y) = self.subpixel_offset(x, y, frame, rng);
+            let ray = self.camera_ray(
+                camera,
+                x as Float + rand_x,
+                y as Float + rand_y,
+                width,
+                height,
+            );
+            let ray = self.lens_ray(camera, ray, rng);
+            results.push(self.trace_multi_ray(scene, ray, rng));
+        }
+        RayTraceResult::average(results)
+    }
+
+    // Wavefront version of `trace_partial_image`: generate every primary ray up front,
+    // then trace each round of samples for all pixels that still need one together.
+    fn trace_pixels_wavefront(
+        &self,
+        scene: &Scene,
+        fb: &mut dyn Framebuffer,
+        camera: &Transform,
+        rng: &mut dyn RngCore,
+        pixels: Vec<(u16, u16)>,
+        frame: u32,
+    ) -> RenderStats {
+        let width = fb.width();
+        let height = fb.height();
+        // Pixels traced more than once get an entry in the queue per sample.
+        let mut entries = Vec::new();
+        let mut rays = Vec::new();
+        let mut rngs = Vec::new();
+        for (index, &(x, y)) in pixels.iter().enumerate() {
+            let count = self.pixel_sample_count(scene, camera, x, y, width, height);
+            for i in 0..count {
+                let frame = frame * count as u32 + i as u32;
+                let mut pixel_rng = match self.seed {
+                    Some(seed) => StdRng::seed_from_u64(pixel_seed(seed, x, y, frame)),
+                    None => StdRng::from_rng(&mut *rng).unwrap(),
+                };
+                let (rand_x, rand_y) = self.subpixel_offset(x, y, frame, &mut pixel_rng);
+                let ray = self.camera_ray(
+                    camera,
+                    x as Float + rand_x,
+                    y as Float + rand_y,
+                    width,
+                    height,
+                );
+                rays.push(self.lens_ray(camera, ray, &mut pixel_rng));
+                rngs.push(pixel_rng);
+                entries.push(index);
+            }
+        }
+
+        let firsts = self.trace_wavefront(scene, &rays, &mut rngs);
+        let counts: Vec<u16> = firsts
+            .iter()
+            .map(|first| self.extra_samples(first))
+            .collect();
+        let mut samples: Vec<Vec<RayTraceResult>> = counts.iter().map(|_| Vec::new()).collect();
+        for round in 0..counts.iter().copied().max().unwrap_or(0) {
+            let indices: Vec<usize> = (0..entries.len()).filter(|&i| counts[i] > round).collect();
+            let round_rays: Vec<Ray> = indices.iter().map(|&i| rays[i]).collect();
+            let mut round_rngs: Vec<StdRng> = indices.iter().map(|&i| rngs[i].clone()).collect();
+            let results = self.trace_wavefront(scene, &round_rays, &mut round_rngs);
+            for ((&i, pixel_rng), result) in indices.iter().zip(round_rngs).zip(results) {
+                rngs[i] = pixel_rng;
+                samples[i].push(result);
+            }
+        }
+
+        let mut results: Vec<Vec<RayTraceResult>> = pixels.iter().map(|_| Vec::new()).collect();
+        for ((index, first), samples) in entries.into_iter().zip(firsts).zip(samples) {
+            results[index].push(self.combine_samples(first, samples));
+        }
+        let mut stats = RenderStats::default();
+        for ((x, y), results) in pixels.into_iter().zip(results) {
+            let result = RayTraceResult::average(results);
+            fb.set_pixel(x, y, result.color);
+            fb.set_aovs(x, y, &result.aovs);
+            stats += result.stats;
+        }
+        stats
+    }
+
+    // Number of times to trace a pixel: enough for its priority, and at least `edge_samples`
+    // if it is on a geometric edge.
+    fn pixel_sample_count(
+        &self,
+        scene: &Scene,
+        camera: &Transform,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    ) -> u16 {
+        let count = match &self.priority {
+            Some(priority) => (priority.get(x, y, width, height) * self.priority_samples as Float)
+                .round()
+                .max(1.0) as u16,
+            None => 1,
+        };
+        if self.edge_samples <= 1 {
+            return count;
+        }
+        // Object, normal and distance of the first hit through the center of a pixel.
+        let probe = |x: u16, y: u16| {
+            let ray = self.camera_ray(camera, x as Float, y as Float, width, height);
+            self.get_intersection(scene, ray, RayKind::Camera)
+                .map(|hit| (hit.object_id, hit.normal, hit.distance))
+        };
+        let center = probe(x, y);
+        let neighbors = [
+            (x > 0).then(|| probe(x - 1, y)),
+            (x + 1 < width).then(|| probe(x + 1, y)),
+            (y > 0).then(|| probe(x, y - 1)),
+            (y + 1 < height).then(|| probe(x, y + 1)),
+        ];
+        let is_edge = neighbors
+            .into_iter()
+            .flatten()
+            .any(|other| match (center, other) {
+                (None, None) => false,
+                (Some((id0, normal0, dist0)), Some((id1, normal1, dist1))) => {
+                    id0 != id1
+                        || normal0.dot(normal1) < EDGE_MIN_NORMAL_DOT
+                        || (dist0 - dist1).abs() > EDGE_MAX_DEPTH_RATIO * dist0.min(dist1)
+                }
+                _ => true,
+            });
+        if is_edge {
+            self.edge_samples.max(count)
+        } else {
+            count
+        }
+    }
+
+    /// Get the average color of one sample through the center of each pixel of an image.
+    /// With `render_mode` set to `RenderMode::Furnace`, this is 1 in every channel if no energy
+    /// is lost or created, except for paths cut short by `max_depth` or `min_contribution`.
+    pub fn furnace_average(
+        &self,
+        scene: &Scene,
+        camera: &Transform,
+        width: u16,
+        height: u16,
+        rng: &mut dyn RngCore,
+    ) -> Vector<3> {
+        let mut sum = vector![0, 0, 0];
+        for y in 0..height {
+            for x in 0..width {
+                let ray =
+                    self.camera_ray(camera, x as Float + 0.5, y as Float + 0.5, width, height);
+                sum += self.trace_single_ray(scene, ray, rng).color;
+            }
+        }
+        sum / (width as Float * height as Float)
+    }
+
+    /// Ray-trace a coarse preview of an image, with one sample per `preview_scale` square of pixels.
+    /// Meant to be drawn straight to the display before the first full-resolution frame.
+    pub fn trace_preview(
+        &self,
+        scene: &Scene,
+        fb: &mut dyn Framebuffer,
+        camera: &Transform,
+        rng: &mut dyn RngCore,
     ) {
         let width = fb.width();
         let height = fb.height();
-        let fov = self.fov.to_radians() * 0.5;
-        let distance = 0.5 / fov.tan() * width as f64;
-        for y in bounds.1..(bounds.1 + bounds.3) {
-            for x in bounds.0..(bounds.0 + bounds.2) {
-                if (x as usize + y as usize * width as usize) % interlace_amount as usize
-                    != interlace_offset as usize
-                {
-                    continue;
+        let scale = self.preview_scale.max(1);
+        for block_y in (0..height).step_by(scale as usize) {
+            for block_x in (0..width).step_by(scale as usize) {
+                let block_w = scale.min(width - block_x);
+                let block_h = scale.min(height - block_y);
+                let ray = self.camera_ray(
+                    camera,
+                    block_x as Float + block_w as Float * 0.5 - 0.5,
+                    block_y as Float + block_h as Float * 0.5 - 0.5,
+                    width,
+                    height,
+                );
+                let col = self.trace_single_ray(scene, ray, rng).color;
+                for y in block_y..block_y + block_h {
+                    for x in block_x..block_x + block_w {
+                        fb.set_pixel(x, y, col);
+                    }
                 }
-                let rand_x = rng.next_u32() as f64 / (1u64 << 32) as f64 - 0.5;
-                let rand_y = rng.next_u32() as f64 / (1u64 << 32) as f64 - 0.5;
-                let ray = camera.ray_local_to_world(Ray {
-                    pos: vector![0, 0, 0],
-                    normal: vector![
-                        rand_x + x as f64 - width as f64 * 0.5,
-                        rand_y + y as f64 - height as f64 * 0.5,
-                        distance
-                    ]
-                    .as_unit_vector(),
-                });
-                fb.set_pixel(x, y, self.trace_multi_ray(scene, ray, rng).color);
             }
         }
     }
+
+    /// Get the offset from the pixel center to sample for the given pass.
+    /// This is from -0.5 to 0.5 on both axes for the box filter, and wider for other filters.
+    pub fn subpixel_offset(
+        &self,
+        x: u16,
+        y: u16,
+        frame: u32,
+        rng: &mut dyn RngCore,
+    ) -> (Float, Float) {
+        let (x, y) = match self.sample_pattern {
+            SamplePattern::White => (
+                rng.next_u32() as Float / (1u64 << 32) as Float - 0.5,
+                rng.next_u32() as Float / (1u64 << 32) as Float - 0.5,
+            ),
+            SamplePattern::Blue => {
+                // Interleaved gradient noise per pixel, shifted along the R2 sequence per pass.
+                let ign = |x: Float, y: Float| {
+                    (52.9829189 * (0.06711056 * x + 0.00583715 * y).fract()).fract()
+                };
+                let (x, y) = (x as Float, y as Float);
+                (
+                    (ign(x, y) + frame as Float * 0.7548776662).fract() - 0.5,
+                    (ign(y + 47.0, x + 17.0) + frame as Float * 0.5698402910).fract() - 0.5,
+                )
+            }
+            SamplePattern::Stratified => {
+                // Visit each cell of a 4x4 grid once every 16 passes, starting at a per-pixel cell.
+                let cell = (frame as usize + x as usize * 7 + y as usize * 13) % 16;
+                (
+                    ((cell % 4) as Float + rng.gen::<Float>()) / 4.0 - 0.5,
+                    ((cell / 4) as Float + rng.gen::<Float>()) / 4.0 - 0.5,
+                )
+            }
+            SamplePattern::Halton => {
+                // Cranley-Patterson rotation: shift both dimensions by a hash of the pixel,
+                // wrapping around, which keeps the points evenly spread.
+                let hash = pixel_seed(0, x, y, 0);
+                let shift_x = (hash >> 32) as Float / (1u64 << 32) as Float;
+                let shift_y = (hash & 0xffffffff) as Float / (1u64 << 32) as Float;
+                (
+                    (radical_inverse(2, frame) + shift_x).fract() - 0.5,
+                    (radical_inverse(3, frame) + shift_y).fract() - 0.5,
+                )
+            }
+        };
+        self.pixel_filter.warp(x, y)
+    }
+
+    /// Move `camera` back along its Z axis until the bounding sphere of every enabled object
+    /// fits in the horizontal field of view, keeping its rotation.
+    /// Does nothing if no objects are enabled.
+    pub fn frame_scene(&self, camera: &mut Transform, scene: &Scene) {
+        let Some((min, max)) = scene.bounding_box() else {
+            return;
+        };
+        let center = (min + max) * 0.5;
+        let radius = (max - min).magnitude() * 0.5;
+        let distance = radius / (self.fov.to_radians() * 0.5).sin();
+        let forward = camera.forward();
+        camera.set_pos(center - forward * distance);
+    }
+
+    /// Move a pinhole camera ray to a random point on the lens, keeping the point it passes
+    /// at `focus_distance` the same. Returns the ray unchanged if `aperture` is 0
+    /// or the projection is equirectangular.
+    pub fn lens_ray(&self, camera: &Transform, ray: Ray, rng: &mut dyn RngCore) -> Ray {
+        if self.aperture <= 0.0 || self.projection == Projection::Equirectangular {
+            return ray;
+        }
+        let forward = camera.forward();
+        let focus = ray.pos + ray.normal * (self.focus_distance / ray.normal.dot(forward));
+        let (lens_x, lens_y) = self.bokeh_shape.sample(rng);
+        let pos =
+            ray.pos + camera.normal_local_to_world(vector![lens_x, lens_y, 0]) * self.aperture;
+        Ray {
+            pos,
+            normal: (focus - pos).as_unit_vector(),
+        }
+    }
+
+    /// Get the world-space camera ray through a point on the image plane, in pixels.
+    pub fn camera_ray(
+        &self,
+        camera: &Transform,
+        x: Float,
+        y: Float,
+        width: u16,
+        height: u16,
+    ) -> Ray {
+        if self.projection == Projection::Equirectangular {
+            let uv = Vector::new([x / width as Float, y / height as Float]);
+            return camera.ray_local_to_world(Ray {
+                pos: vector![0, 0, 0],
+                normal: equirect_uv_to_direction(uv),
+            });
+        }
+        let fov = self.fov.to_radians() * 0.5;
+        let distance = 0.5 / fov.tan() * width as Float;
+        let x = x - width as Float * 0.5;
+        let y = y - height as Float * 0.5;
+        // Scale by 1 + k * r^2, with r being 1 at the left and right edges of the image.
+        let radius_sqr = (x * x + y * y) / (width as Float * width as Float * 0.25);
+        let warp = 1.0 + self.distortion * radius_sqr;
+        camera.ray_local_to_world(Ray {
+            pos: vector![0, 0, 0],
+            normal: vector![x * warp, y * warp, distance].as_unit_vector(),
+        })
+    }
 }
 
 pub struct RayTraceResult {
     pub color: Vector<3>,
     pub did_reflect: bool,
     pub did_refract: bool,
+    /// Highest roughness of the surfaces the path reflected off.
+    pub roughness: Float,
+    /// Separate render layers, indexed by `Aov`.
+    pub aovs: [Vector<3>; AOV_COUNT],
+    /// Work done to trace this result.
+    pub stats: RenderStats,
+}
+
+impl RayTraceResult {
+    // Average the results of several samples of one pixel, adding up their stats.
+    fn average(results: Vec<RayTraceResult>) -> RayTraceResult {
+        let count = results.len() as Float;
+        let mut results = results.into_iter();
+        let mut out = results.next().unwrap();
+        for result in results {
+            out.color += result.color;
+            for i in 0..AOV_COUNT {
+                out.aovs[i] += result.aovs[i];
+            }
+            out.did_reflect |= result.did_reflect;
+            out.did_refract |= result.did_refract;
+            out.roughness = out.roughness.max(result.roughness);
+            out.stats += result.stats;
+        }
+        out.color /= count;
+        for aov in &mut out.aovs {
+            *aov /= count;
+        }
+        out
+    }
+
+    // Add light that reached the camera after bouncing off `hits` surfaces.
+    fn add_light(&mut self, light: Vector<3>, hits: u32) {
+        self.color += light;
+        let aov = match hits {
+            0 => Aov::Emission,
+            1 => Aov::Direct,
+            _ => Aov::Indirect,
+        };
+        self.aovs[aov as usize] += light;
+    }
+}
+
+/// State of a path being traced, so that it can be advanced one surface hit at a time.
+struct PathState {
+    ray: Ray,
+    kind: RayKind,
+    result: RayTraceResult,
+    hits: u32,
+    color_mask: Vector<3>,
+    depth: u16,
+    reflect: u16,
+    refract: u16,
+    // Indices of refraction of the media the ray is currently inside, innermost last.
+    // Each entry also holds the dispersion of that medium.
+    ior_stack: Vec<(Float, Float)>,
+    // Wavelength of this path in micrometers, once it refracts through a dispersive object.
+    wavelength: Option<Float>,
+    // Largest roughness of any bounce so far, for regularization.
+    path_roughness: Float,
+    // Probability density of the last bounce direction, if the environment was also sampled
+    // directly there.
+    bounce_pdf: Option<Float>,
+    // The ray this path started with if it is a camera path, and the distance to its first hit.
+    camera_ray: Option<Ray>,
+    first_distance: Option<Float>,
+    // Whether the last diffuse bounce gathered from the photon map, and if so, whether the path
+    // has since hit only specular surfaces, so that a light hit now is a caustic already added.
+    caustic: Option<bool>,
+}
+
+impl PathState {
+    fn new(tracer: &Tracer, ray: Ray, kind: RayKind) -> PathState {
+        PathState {
+            ray,
+            kind,
+            result: RayTraceResult {
+                color: vector![0, 0, 0],
+                did_reflect: false,
+                did_refract: false,
+                roughness: 0.0,
+                aovs: [vector![0, 0, 0]; AOV_COUNT],
+                stats: RenderStats {
+                    primary_rays: (kind == RayKind::Camera) as u64,
+                    ..RenderStats::default()
+                },
+            },
+            hits: 0,
+            color_mask: vector![1, 1, 1],
+            depth: tracer.max_depth,
+            reflect: tracer.max_reflect,
+            refract: tracer.max_refract,
+            ior_stack: Vec::new(),
+            wavelength: None,
+            path_roughness: 0.0,
+            bounce_pdf: None,
+            camera_ray: Some(ray).filter(|_| kind == RayKind::Camera),
+            first_distance: None,
+            caustic: None,
+        }
+    }
+}
+
+/// Counts of the work done by a render, to see where the time goes.
+#[derive(Clone, Copy, Default)]
+pub struct RenderStats {
+    /// Rays traced from the camera, including every extra sample per pixel.
+    pub primary_rays: u64,
+    /// Surfaces hit along all paths.
+    pub bounces: u64,
+    /// Tests of a ray against a single object, including for shadow rays.
+    pub intersection_tests: u64,
+    /// Time the whole render took; zero for the stats of a single result.
+    pub wall_time: Duration,
+}
+
+impl AddAssign for RenderStats {
+    fn add_assign(&mut self, other: RenderStats) {
+        self.primary_rays += other.primary_rays;
+        self.bounces += other.bounces;
+        self.intersection_tests += other.intersection_tests;
+        self.wall_time += other.wall_time;
+    }
+}
+
+impl Display for RenderStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let per_ray = |count: u64| count as Float / self.primary_rays.max(1) as Float;
+        write!(
+            f,
+            "{} primary rays, {} bounces ({:.2} per ray), {} intersection tests ({:.1} per ray) \
+             in {:.3} s",
+            self.primary_rays,
+            self.bounces,
+            per_ray(self.bounces),
+            self.intersection_tests,
+            per_ray(self.intersection_tests),
+            self.wall_time.as_secs_f64()
+        )
+    }
+}
+
+/// Render layers output alongside the beauty image for compositing.
+/// `Direct`, `Indirect` and `Emission` add up to the beauty image.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Aov {
+    /// Light reaching the first surface straight from the sky or an emissive object.
+    Direct,
+    /// Light that bounced off more than one surface.
+    Indirect,
+    /// Emissive objects and sky seen directly by the camera.
+    Emission,
+    /// Fraction of samples whose bounce off the first surface was blocked by another object.
+    Shadow,
+    /// Coverage of camera rays: 1 on objects, 0 on the sky, and the shadow on shadow catchers.
+    Alpha,
+}
+
+pub const AOV_COUNT: usize = 5;
+
+/// Result of comparing two images pixel by pixel.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ImageDiff {
+    /// Largest per-channel difference of any pixel.
+    pub max_error: Float,
+    /// Average over all pixels of the largest per-channel difference.
+    pub mean_error: Float,
+    /// Number of pixels whose difference exceeds the tolerance.
+    pub failed_pixels: usize,
+}
+
+impl ImageDiff {
+    pub fn passed(&self) -> bool {
+        self.failed_pixels == 0
+    }
+}
+
+/// Compare two images of the same size, allowing each channel to differ by up to `tol`.
+pub fn compare_images(a: &[Vector<3>], b: &[Vector<3>], tol: Float) -> ImageDiff {
+    assert_eq!(a.len(), b.len());
+    let mut diff = ImageDiff {
+        max_error: 0.0,
+        mean_error: 0.0,
+        failed_pixels: 0,
+    };
+    for (a, b) in a.iter().zip(b) {
+        let mut error = 0.0 as Float;
+        for i in 0..3 {
+            error = error.max((a[i] - b[i]).abs());
+        }
+        diff.max_error = diff.max_error.max(error);
+        diff.mean_error += error;
+        if error > tol || error.is_nan() {
+            diff.failed_pixels += 1;
+        }
+    }
+    if !a.is_empty() {
+        diff.mean_error /= a.len() as Float;
+    }
+    diff
+}
+
+/// Transfer function applied to linear color when converting it for display or 8-bit output.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputColorSpace {
+    /// No transfer function.
+    Linear,
+    /// The piecewise sRGB curve, linear below 0.0031308.
+    Srgb,
+    /// The Rec. 709 camera curve, linear below 0.018.
+    Rec709,
+}
+
+impl OutputColorSpace {
+    /// Encode a linear color in this color space. Negative components become zero.
+    pub fn encode(&self, linear: Vector<3>) -> Vector<3> {
+        let transfer = |c: Float| -> Float {
+            let c = c.max(0.0);
+            match self {
+                OutputColorSpace::Linear => c,
+                OutputColorSpace::Srgb if c <= 0.0031308 => c * 12.92,
+                OutputColorSpace::Srgb => 1.055 * c.powf(1.0 / 2.4) - 0.055,
+                OutputColorSpace::Rec709 if c < 0.018 => c * 4.5,
+                OutputColorSpace::Rec709 => 1.099 * c.powf(0.45) - 0.099,
+            }
+        };
+        Vector::new(linear.data().map(transfer))
+    }
+}
+
+/// Synthetic images for checking the output pipeline without tracing.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TestPattern {
+    /// Black to white ramp from left to right.
+    Gradient,
+    /// Vertical bars of white, yellow, cyan, green, magenta, red, blue and black.
+    ColorBars,
+    /// U and V in the red and green channels with white lines every eighth.
+    UvGrid,
 }
 
 pub trait Framebuffer {
     fn width(&self) -> u16;
     fn height(&self) -> u16;
     fn set_pixel(&mut self, x: u16, y: u16, col: Vector<3>);
+    /// Read back the color of a pixel.
+    fn get_pixel(&self, x: u16, y: u16) -> Vector<3>;
+    /// Whether this framebuffer stores render layers; tracing skips them otherwise.
+    fn wants_aovs(&self) -> bool {
+        false
+    }
+    /// Store the render layers of a pixel, indexed by `Aov`.
+    fn set_aovs(&mut self, _x: u16, _y: u16, _aovs: &[Vector<3>; AOV_COUNT]) {}
+
+    /// Write a test pattern to every pixel.
+    fn fill_test_pattern(&mut self, pattern: TestPattern) {
+        let width = self.width();
+        let height = self.height();
+        for y in 0..height {
+            for x in 0..width {
+                let u = (x as Float + 0.5) / width as Float;
+                let v = (y as Float + 0.5) / height as Float;
+                let col = match pattern {
+                    TestPattern::Gradient => vector![u, u, u],
+                    TestPattern::ColorBars => {
+                        let bar = 7 - (u * 8.0) as u8;
+                        vector![bar >> 1 & 1, bar >> 2 & 1, bar & 1]
+                    }
+                    TestPattern::UvGrid => {
+                        let line_u = (x as usize * 8 / width as usize)
+                            != ((x as usize + 1) * 8 / width as usize);
+                        let line_v = (y as usize * 8 / height as usize)
+                            != ((y as usize + 1) * 8 / height as usize);
+                        if line_u || line_v {
+                            vector![1, 1, 1]
+                        } else {
+                            vector![u, v, 0]
+                        }
+                    }
+                };
+                self.set_pixel(x, y, col);
+            }
+        }
+    }
 }
 
 pub struct PartialFramebuffer {
-    data: Vec<[f64; 3]>,
+    data: Vec<[Float; 3]>,
+    aovs: Option<Vec<[Vector<3>; AOV_COUNT]>>,
     width: u16,
     height: u16,
     interlace_count: u16,
@@ -300,7 +2291,8 @@ impl PartialFramebuffer {
             length /= interlace_count as usize;
         }
         PartialFramebuffer {
-            data: vec![[0f64; 3]; length],
+            data: vec![[0.0 as Float; 3]; length],
+            aovs: None,
             width,
             height,
             interlace_count,
@@ -322,8 +2314,16 @@ impl PartialFramebuffer {
             let x = (index % self.width as usize) as u16;
             let y = (index / self.width as usize) as u16;
             other.set_pixel(x, y, Vector::from(self.data[i]));
+            if let Some(aovs) = &self.aovs {
+                other.set_aovs(x, y, &aovs[i]);
+            }
         }
     }
+
+    /// Also store render layers for each pixel.
+    pub fn enable_aovs(&mut self) {
+        self.aovs = Some(vec![[vector![0, 0, 0]; AOV_COUNT]; self.data.len()]);
+    }
 }
 
 impl Framebuffer for PartialFramebuffer {
@@ -343,10 +2343,118 @@ impl Framebuffer for PartialFramebuffer {
         index /= self.interlace_count as usize;
         self.data[index] = col.data();
     }
+
+    /// Pixels outside this part of the interlace read as black.
+    fn get_pixel(&self, x: u16, y: u16) -> Vector<3> {
+        let index = x as usize + y as usize * self.width as usize;
+        if index % self.interlace_count as usize != self.interlace_offset as usize {
+            return vector![0, 0, 0];
+        }
+        Vector::from(self.data[index / self.interlace_count as usize])
+    }
+
+    fn wants_aovs(&self) -> bool {
+        self.aovs.is_some()
+    }
+
+    fn set_aovs(&mut self, x: u16, y: u16, aovs: &[Vector<3>; AOV_COUNT]) {
+        let index = x as usize + y as usize * self.width as usize;
+        if index % self.interlace_count as usize != self.interlace_offset as usize {
+            return;
+        }
+        if let Some(data) = &mut self.aovs {
+            data[index / self.interlace_count as usize] = *aovs;
+        }
+    }
+}
+
+/// Framebuffer that writes 8-bit RGBA pixels into a borrowed byte slice, such as the pixel
+/// buffer of another application's window, with alpha always 255.
+pub struct SliceFramebuffer<'a> {
+    data: &'a mut [u8],
+    width: u16,
+    height: u16,
+    /// Bytes from the start of one row to the start of the next.
+    stride: usize,
+    color_space: OutputColorSpace,
+}
+
+impl<'a> SliceFramebuffer<'a> {
+    /// Wrap `data` as a `width` by `height` image with rows `stride` bytes apart.
+    /// Fails if a row doesn't fit in the stride or the last row doesn't fit in `data`.
+    pub fn new(
+        data: &'a mut [u8],
+        width: u16,
+        height: u16,
+        stride: usize,
+    ) -> Result<SliceFramebuffer<'a>, String> {
+        let row_length = width as usize * 4;
+        if stride < row_length {
+            return Err(format!(
+                "stride of {} bytes is less than a row of {} bytes",
+                stride, row_length
+            ));
+        }
+        let length = match height {
+            0 => 0,
+            _ => stride * (height as usize - 1) + row_length,
+        };
+        if data.len() < length {
+            return Err(format!(
+                "buffer of {} bytes is too small for {}x{} pixels with a stride of {} bytes",
+                data.len(),
+                width,
+                height,
+                stride
+            ));
+        }
+        Ok(SliceFramebuffer {
+            data,
+            width,
+            height,
+            stride,
+            color_space: OutputColorSpace::Linear,
+        })
+    }
+
+    /// Set the color space pixels are encoded in before converting them to bytes.
+    pub fn set_color_space(&mut self, color_space: OutputColorSpace) {
+        self.color_space = color_space;
+    }
+
+    fn index(&self, x: u16, y: u16) -> usize {
+        assert!(x < self.width && y < self.height);
+        y as usize * self.stride + x as usize * 4
+    }
+}
+
+impl Framebuffer for SliceFramebuffer<'_> {
+    fn width(&self) -> u16 {
+        self.width
+    }
+    fn height(&self) -> u16 {
+        self.height
+    }
+    fn set_pixel(&mut self, x: u16, y: u16, col: Vector<3>) {
+        let index = self.index(x, y);
+        let color = vector_to_rgb(self.color_space.encode(col));
+        self.data[index..index + 4].copy_from_slice(&[color.r, color.g, color.b, color.a]);
+    }
+    /// Returns the stored bytes scaled to 0 to 1, still in the output color space.
+    fn get_pixel(&self, x: u16, y: u16) -> Vector<3> {
+        let index = self.index(x, y);
+        let pixel = &self.data[index..index + 3];
+        vector![pixel[0], pixel[1], pixel[2]] / 255.0
+    }
 }
 
 pub struct SmoothingFramebuffer {
     buffer: Vec<Vector<3>>,
+    /// One buffer parallel to `buffer` per `Aov`, or empty if render layers are disabled.
+    aovs: Vec<Vec<Vector<3>>>,
+    /// Whether `save_png` writes the alpha layer.
+    alpha: bool,
+    color_space: OutputColorSpace,
     frame: u16,
     width: u16,
     height: u16,
@@ -356,26 +2464,297 @@ impl SmoothingFramebuffer {
     pub fn new(width: u16, height: u16) -> SmoothingFramebuffer {
         SmoothingFramebuffer {
             buffer: vec![vector![0, 0, 0]; width as usize * height as usize],
+            aovs: Vec::new(),
+            alpha: false,
+            color_space: OutputColorSpace::Linear,
             frame: 0,
             width: width,
             height: height,
         }
     }
 
+    /// Count another frame as accumulated and draw the average so far to `out`.
+    /// If `out` is a different size, such as a resized window, the image is stretched to fit
+    /// with bilinear filtering.
     pub fn update(&mut self, out: &mut dyn Framebuffer) {
         self.frame += 1;
-        let scale = 1.0 / self.frame as f64;
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let col = self.buffer[y as usize * self.width as usize + x as usize];
-                out.set_pixel(x, y, col * scale);
+        let scale = 1.0 / self.frame as Float;
+        if out.width() == self.width && out.height() == self.height {
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    let col = self.buffer[y as usize * self.width as usize + x as usize];
+                    out.set_pixel(x, y, self.color_space.encode(col * scale));
+                }
+            }
+            return;
+        }
+        if self.width == 0 || self.height == 0 {
+            return;
+        }
+        let (out_width, out_height) = (out.width(), out.height());
+        for y in 0..out_height {
+            for x in 0..out_width {
+                // Map pixel centers onto pixel centers.
+                let src_x = (x as Float + 0.5) * self.width as Float / out_width as Float - 0.5;
+                let src_y = (y as Float + 0.5) * self.height as Float / out_height as Float - 0.5;
+                let col = self.bilinear(src_x, src_y);
+                out.set_pixel(x, y, self.color_space.encode(col * scale));
             }
         }
     }
 
+    // Interpolate the accumulated sums between the four pixels around a point,
+    // repeating the edge pixels past the borders.
+    fn bilinear(&self, x: Float, y: Float) -> Vector<3> {
+        let x = x.clamp(0.0, (self.width - 1) as Float);
+        let y = y.clamp(0.0, (self.height - 1) as Float);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let x1 = (x0 + 1).min(self.width as usize - 1);
+        let y1 = (y0 + 1).min(self.height as usize - 1);
+        let (tx, ty) = (x - x0 as Float, y - y0 as Float);
+        let get = |x: usize, y: usize| self.buffer[y * self.width as usize + x];
+        let top = get(x0, y0) * (1.0 - tx) + get(x1, y0) * tx;
+        let bottom = get(x0, y1) * (1.0 - tx) + get(x1, y1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+
+    /// Set the color space used by `update` and `save_png`. EXR output always stays linear.
+    pub fn set_color_space(&mut self, color_space: OutputColorSpace) {
+        self.color_space = color_space;
+    }
+
     pub fn get_frame(&self) -> u16 {
         self.frame
     }
+
+    /// Discard everything accumulated so far, for when the scene changes.
+    pub fn reset(&mut self) {
+        self.buffer.fill(vector![0, 0, 0]);
+        for aov in &mut self.aovs {
+            aov.fill(vector![0, 0, 0]);
+        }
+        self.frame = 0;
+    }
+
+    /// Change the size of the image, discarding everything accumulated so far.
+    /// Render layers and other settings are kept.
+    pub fn resize(&mut self, width: u16, height: u16) {
+        let length = width as usize * height as usize;
+        self.buffer = vec![vector![0, 0, 0]; length];
+        for aov in &mut self.aovs {
+            *aov = vec![vector![0, 0, 0]; length];
+        }
+        self.width = width;
+        self.height = height;
+        self.frame = 0;
+    }
+
+    /// Get the raw color sums of every pixel, before dividing by the frame count.
+    pub fn accumulated(&self) -> &[Vector<3>] {
+        &self.buffer
+    }
+
+    /// Save the raw accumulated colors and frame count, so the render can be resumed with `load_state`.
+    /// Render layers are not saved, and values are stored at the precision of `Float`.
+    pub fn save_state(&self, path: &str) -> Result<(), String> {
+        let mut out = Vec::with_capacity(10 + self.buffer.len() * 3 * size_of::<Float>());
+        out.extend_from_slice(b"SFB1");
+        out.extend_from_slice(&self.width.to_le_bytes());
+        out.extend_from_slice(&self.height.to_le_bytes());
+        out.extend_from_slice(&self.frame.to_le_bytes());
+        for col in &self.buffer {
+            for value in col.data() {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        std::fs::write(path, out).map_err(|e| e.to_string())
+    }
+
+    /// Replace the accumulated colors and frame count with those saved by `save_state`.
+    /// The saved dimensions must match this framebuffer. Render layers are disabled.
+    pub fn load_state(&mut self, path: &str) -> Result<(), String> {
+        let data = std::fs::read(path).map_err(|e| e.to_string())?;
+        if data.len() < 10 || &data[0..4] != b"SFB1" {
+            return Err(format!("{} is not a framebuffer state file", path));
+        }
+        let width = u16::from_le_bytes([data[4], data[5]]);
+        let height = u16::from_le_bytes([data[6], data[7]]);
+        if width != self.width || height != self.height {
+            return Err(format!(
+                "state is {}x{}, but the framebuffer is {}x{}",
+                width, height, self.width, self.height
+            ));
+        }
+        let size = size_of::<Float>();
+        if data.len() != 10 + self.buffer.len() * 3 * size {
+            return Err(format!(
+                "{} has the wrong length, or was saved with a different float precision",
+                path
+            ));
+        }
+        self.frame = u16::from_le_bytes([data[8], data[9]]);
+        for (col, chunk) in self.buffer.iter_mut().zip(data[10..].chunks(3 * size)) {
+            for i in 0..3 {
+                let bytes = chunk[i * size..(i + 1) * size].try_into().unwrap();
+                col[i] = Float::from_le_bytes(bytes);
+            }
+        }
+        self.aovs.clear();
+        self.alpha = false;
+        Ok(())
+    }
+
+    /// Add the passes accumulated in `other`, for example on another machine, to this framebuffer.
+    /// Render layers are only kept if both framebuffers have them.
+    pub fn merge(&mut self, other: &SmoothingFramebuffer) {
+        assert_eq!(self.width, other.width);
+        assert_eq!(self.height, other.height);
+        for (col, other_col) in self.buffer.iter_mut().zip(&other.buffer) {
+            *col += *other_col;
+        }
+        if other.aovs.is_empty() {
+            self.aovs.clear();
+            self.alpha = false;
+        }
+        for (layer, other_layer) in self.aovs.iter_mut().zip(&other.aovs) {
+            for (col, other_col) in layer.iter_mut().zip(other_layer) {
+                *col += *other_col;
+            }
+        }
+        self.frame = self.frame.saturating_add(other.frame);
+    }
+
+    /// Start accumulating render layers next to the beauty image.
+    pub fn enable_aovs(&mut self) {
+        self.aovs = vec![self.buffer.iter().map(|_| vector![0, 0, 0]).collect(); AOV_COUNT];
+    }
+
+    /// Read back the color of a pixel in a render layer, averaged over all frames so far.
+    /// Returns black if render layers are disabled.
+    pub fn get_aov(&self, aov: Aov, x: u16, y: u16) -> Vector<3> {
+        match self.aovs.get(aov as usize) {
+            Some(layer) => layer[y as usize * self.width as usize + x as usize] / self.frame.max(1),
+            None => vector![0, 0, 0],
+        }
+    }
+
+    /// Accumulate coverage, with 0 for the background and 1 for objects, so `save_png` writes RGBA.
+    /// Coverage is kept as the alpha render layer, so this also enables the other render layers.
+    pub fn enable_alpha(&mut self) {
+        if self.aovs.is_empty() {
+            self.enable_aovs();
+        }
+        self.alpha = true;
+    }
+
+    /// Save the averaged color as an 8-bit RGB PNG image,
+    /// or RGBA with straight alpha if `enable_alpha` was called.
+    pub fn save_png(&self, path: &str) -> Result<(), String> {
+        if self.alpha {
+            self.save_rgba_png(path)
+        } else {
+            self.save_layer_png(&self.buffer, path)
+        }
+    }
+
+    /// Save a render layer as an 8-bit RGB PNG image.
+    pub fn save_aov_png(&self, aov: Aov, path: &str) -> Result<(), String> {
+        match self.aovs.get(aov as usize) {
+            Some(layer) => self.save_layer_png(layer, path),
+            None => Err("render layers are not enabled".to_string()),
+        }
+    }
+
+    fn save_layer_png(&self, layer: &[Vector<3>], path: &str) -> Result<(), String> {
+        let scale = 1.0 / self.frame.max(1) as Float;
+        let mut rgb = Vec::with_capacity(layer.len() * 3);
+        for col in layer {
+            let col = vector_to_rgb(self.color_space.encode(*col * scale));
+            rgb.extend_from_slice(&[col.r, col.g, col.b]);
+        }
+        std::fs::write(path, encode_png(self.width, self.height, false, &rgb))
+            .map_err(|e| e.to_string())
+    }
+
+    fn save_rgba_png(&self, path: &str) -> Result<(), String> {
+        let alpha = &self.aovs[Aov::Alpha as usize];
+        let scale = 1.0 / self.frame.max(1) as Float;
+        let mut rgba = Vec::with_capacity(self.buffer.len() * 4);
+        for (col, alpha) in self.buffer.iter().zip(alpha) {
+            let col = self.color_space.encode(*col * scale);
+            let col = vector_to_rgba(vector![col[0], col[1], col[2], alpha[0] * scale]);
+            rgba.extend_from_slice(&[col.r, col.g, col.b, col.a]);
+        }
+        std::fs::write(path, encode_png(self.width, self.height, true, &rgba))
+            .map_err(|e| e.to_string())
+    }
+
+    /// Save the averaged linear color as an uncompressed 32-bit float RGB OpenEXR image.
+    /// No tone mapping or gamma is applied.
+    #[cfg(feature = "exr")]
+    pub fn save_exr(&self, path: &str) -> Result<(), String> {
+        fn attr(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
+            out.extend_from_slice(kind.as_bytes());
+            out.push(0);
+            out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+            out.extend_from_slice(value);
+        }
+
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let scale = 1.0 / self.frame.max(1) as Float;
+
+        // Magic number and version 2, single-part scanline.
+        let mut out = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+
+        // Channels must be listed in alphabetical order.
+        let mut channels = vec![];
+        for name in ["B", "G", "R"] {
+            channels.extend_from_slice(name.as_bytes());
+            channels.push(0);
+            channels.extend_from_slice(&2i32.to_le_bytes()); // FLOAT
+            channels.extend_from_slice(&[0, 0, 0, 0]); // pLinear, reserved
+            channels.extend_from_slice(&1i32.to_le_bytes()); // xSampling
+            channels.extend_from_slice(&1i32.to_le_bytes()); // ySampling
+        }
+        channels.push(0);
+        let mut window = vec![];
+        for value in [0, 0, width as i32 - 1, height as i32 - 1] {
+            window.extend_from_slice(&value.to_le_bytes());
+        }
+        attr(&mut out, "channels", "chlist", &channels);
+        attr(&mut out, "compression", "compression", &[0]);
+        attr(&mut out, "dataWindow", "box2i", &window);
+        attr(&mut out, "displayWindow", "box2i", &window);
+        attr(&mut out, "lineOrder", "lineOrder", &[0]);
+        attr(&mut out, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+        attr(&mut out, "screenWindowCenter", "v2f", &[0; 8]);
+        attr(&mut out, "screenWindowWidth", "float", &1f32.to_le_bytes());
+        out.push(0);
+
+        // Offset table; every uncompressed scanline is its own block.
+        let line_size = width * 3 * 4;
+        let table_end = out.len() + height * 8;
+        for y in 0..height {
+            let offset = table_end + y * (8 + line_size);
+            out.extend_from_slice(&(offset as u64).to_le_bytes());
+        }
+
+        for y in 0..height {
+            out.extend_from_slice(&(y as i32).to_le_bytes());
+            out.extend_from_slice(&(line_size as i32).to_le_bytes());
+            let row = &self.buffer[y * width..(y + 1) * width];
+            for channel in [2, 1, 0] {
+                for col in row {
+                    out.extend_from_slice(&((col[channel] * scale) as f32).to_le_bytes());
+                }
+            }
+        }
+
+        std::fs::write(path, out).map_err(|e| e.to_string())
+    }
 }
 
 impl Framebuffer for SmoothingFramebuffer {
@@ -390,6 +2769,19 @@ impl Framebuffer for SmoothingFramebuffer {
         assert!(y <= self.height);
         self.buffer[y as usize * self.width as usize + x as usize] += col;
     }
+    /// Returns the color averaged over all frames so far.
+    fn get_pixel(&self, x: u16, y: u16) -> Vector<3> {
+        self.buffer[y as usize * self.width as usize + x as usize] / self.frame.max(1)
+    }
+    fn wants_aovs(&self) -> bool {
+        !self.aovs.is_empty()
+    }
+    fn set_aovs(&mut self, x: u16, y: u16, aovs: &[Vector<3>; AOV_COUNT]) {
+        let index = y as usize * self.width as usize + x as usize;
+        for (layer, col) in self.aovs.iter_mut().zip(aovs) {
+            layer[index] += *col;
+        }
+    }
 }
 
 impl<T: RenderTarget> Framebuffer for Canvas<T> {
@@ -403,4 +2795,778 @@ impl<T: RenderTarget> Framebuffer for Canvas<T> {
         self.set_draw_color(vector_to_rgb(col));
         let _ = self.draw_point(Point::new(x as i32, y as i32));
     }
+    /// Reading back from the GPU is not supported; always returns black.
+    fn get_pixel(&self, _x: u16, _y: u16) -> Vector<3> {
+        vector![0, 0, 0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera_ray(target: Vector<3>) -> Ray {
+        Ray {
+            pos: vector![0, 0, 0],
+            normal: target.as_unit_vector(),
+        }
+    }
+
+    #[test]
+    fn wireframe_lights_the_three_edges_of_a_triangle() {
+        let mut scene = Scene::empty();
+        scene.objects.push(Box::new(Triangle {
+            transform: Transform::from(vector![0, 0, 2], vector![1, 1, 1], vector![0, 0, 0]),
+            enabled: true,
+            material: None,
+            vertices: [vector![-1, 1, 0], vector![1, 1, 0], vector![0, -1, 0]],
+            normals: None,
+            prop: PhysProp::from_color(vector![1, 0, 0]),
+        }));
+        let tracer = Tracer {
+            render_mode: RenderMode::Wireframe { width: 0.05 },
+            ..Tracer::default()
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let centroid = vector![0.0, 1.0 / 3.0, 2.0];
+        let mut color_at = |target: Vector<3>| {
+            tracer
+                .trace_single_ray(&scene, camera_ray(target), &mut rng)
+                .color
+        };
+        assert!(color_at(centroid) == vector![0, 0, 0]);
+        // Just inside the middle of each edge.
+        for edge_middle in [vector![0, 1, 2], vector![0.5, 0, 2], vector![-0.5, 0, 2]] {
+            let target = edge_middle + (centroid - edge_middle) * 0.02;
+            assert!(color_at(target) == vector![1, 1, 1]);
+        }
+        // Missing the triangle altogether.
+        assert!(color_at(vector![2, 0, 2]) == vector![0, 0, 0]);
+    }
+
+    // A white floor facing up at Y = 0 and a mirror facing down at Y = -2.5, with a light
+    // between them that the floor only sees within the 45 degrees diffuse bounces reach
+    // through the mirror.
+    fn mirror_scene() -> Scene {
+        let mut scene = Scene::empty();
+        scene.objects.push(Box::new(Plane {
+            transform: Transform::from(vector![0, 0, 0], vector![10, 10, 10], vector![-90, 0, 0]),
+            enabled: true,
+            material: None,
+            prop: PhysProp::from_color(vector![1, 1, 1]),
+            back_prop: None,
+        }));
+        scene.objects.push(Box::new(Plane {
+            transform: Transform::from(vector![0, -2.5, 0], vector![10, 10, 10], vector![90, 0, 0]),
+            enabled: true,
+            material: None,
+            prop: PhysProp {
+                roughness: 0.0,
+                ..PhysProp::from_color(vector![1, 1, 1])
+            },
+            back_prop: None,
+        }));
+        scene.objects.push(Box::new(Sphere {
+            transform: Transform::from(vector![0, -1, 0], vector![1, 1, 1], vector![0, 0, 0]),
+            enabled: true,
+            material: None,
+            radius: 0.5,
+            prop: PhysProp::from_emission(vector![1, 1, 1], vector![1, 1, 1]),
+        }));
+        scene.find_lights();
+        scene
+    }
+
+    #[test]
+    fn photon_map_matches_a_mirrored_light() {
+        let scene = mirror_scene();
+        let tracer = Tracer::default();
+        let mut rng = StdRng::seed_from_u64(0);
+        let photon_map = tracer.build_photon_map(&scene, 100000, 0.5, &mut rng);
+        // The mirror image of the light is 4 above the floor and 1.5 to the side, and a diffuse
+        // bounce reflects 2/π of the irradiance: 2 * radius² * cos(θ) / distance².
+        let pos = vector![1.5, 0, 0];
+        let distance_sqr: Float = 4.0 * 4.0 + 1.5 * 1.5;
+        let expected = 2.0 * 0.5 * 0.5 * (4.0 / distance_sqr.sqrt()) / distance_sqr;
+        let radiance = photon_map.radiance(pos, vector![0, -1, 0]);
+        assert!(
+            (radiance[0] / expected - 1.0).abs() < 0.15,
+            "{} vs {}",
+            radiance[0],
+            expected
+        );
+        // Lit directly, without the mirror, so not a caustic.
+        assert!(photon_map.radiance(vector![0, 0, 0], vector![0, -1, 0])[0] < expected * 0.1);
+    }
+
+    #[test]
+    fn photon_map_replaces_caustics_found_by_bouncing() {
+        let scene = mirror_scene();
+        // Deep enough for the floor, the mirror and the light.
+        let mut tracer = Tracer {
+            max_depth: 3,
+            ..Tracer::default()
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let ray = Ray {
+            pos: vector![1.5, -1, 0],
+            normal: vector![0, 1, 0],
+        };
+        let mean_color = |tracer: &Tracer, rng: &mut StdRng| {
+            let samples = 20000;
+            let sum = (0..samples).fold(0.0, |sum, _| {
+                sum + tracer.trace_single_ray(&scene, ray, rng).color[0]
+            });
+            sum / samples as Float
+        };
+        let bounced = mean_color(&tracer, &mut rng);
+        tracer.photon_map = Some(tracer.build_photon_map(&scene, 100000, 0.5, &mut rng));
+        let gathered = mean_color(&tracer, &mut rng);
+        assert!(
+            (gathered / bounced - 1.0).abs() < 0.15,
+            "{} vs {}",
+            gathered,
+            bounced
+        );
+    }
+
+    #[test]
+    fn compare_images_of_an_image_with_itself_passes() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let image: Vec<Vector<3>> = (0..64)
+            .map(|_| vector![rng.gen::<Float>(), rng.gen::<Float>(), rng.gen::<Float>()])
+            .collect();
+        let diff = compare_images(&image, &image, 0.0);
+        assert_eq!(diff.max_error, 0.0);
+        assert_eq!(diff.mean_error, 0.0);
+        assert!(diff.passed());
+
+        let mut changed = image.clone();
+        changed[5][1] += 0.5;
+        let diff = compare_images(&image, &changed, 0.1);
+        assert!((diff.max_error - 0.5).abs() < 1e-5);
+        assert!((diff.mean_error - 0.5 / 64.0).abs() < 1e-5);
+        assert_eq!(diff.failed_pixels, 1);
+        assert!(!diff.passed());
+    }
+
+    #[test]
+    fn over_range_colors_saturate_instead_of_wrapping() {
+        let color = vector_to_rgb(vector![2.0, 1.004, 0.5]);
+        assert_eq!((color.r, color.g, color.b), (255, 255, 127));
+        let color = vector_to_rgba(vector![2.0, 0.0, 0.0, 3.0]);
+        assert_eq!((color.r, color.a), (255, 255));
+    }
+
+    #[test]
+    fn color_bytes_clamp_instead_of_wrapping() {
+        let color = vector_to_rgb(vector![1.5, -0.1, 1.5]);
+        assert_eq!((color.r, color.g, color.b), (255, 0, 255));
+        let color = vector_to_rgba(vector![1.5, -0.1, 0.0, -0.1]);
+        assert_eq!((color.r, color.g, color.a), (255, 0, 0));
+    }
+
+    #[test]
+    fn fresnel_schlick_at_normal_and_grazing_incidence() {
+        let f0 = vector![0.04, 0.5, 0.9];
+        assert!((fresnel_schlick(1.0, f0) - f0).magnitude() < 1e-6);
+        let grazing = fresnel_schlick(0.0, f0);
+        assert!((grazing - vector![1, 1, 1]).magnitude() < 1e-6);
+        // Nearly grazing is nearly 1, and it only rises towards grazing.
+        assert!(fresnel_schlick(0.01, f0).min_component() > 0.95);
+        assert!(fresnel_schlick(0.5, f0)[0] < fresnel_schlick(0.2, f0)[0]);
+    }
+
+    // The front sphere of the default scene, lit only by a white sky.
+    fn red_sphere_scene() -> Scene {
+        let mut scene = Scene::empty();
+        scene.skybox_color = vector![1, 1, 1];
+        scene.horizon_color = vector![1, 1, 1];
+        scene.ground_color = vector![1, 1, 1];
+        scene.objects.push(Box::new(Sphere {
+            transform: Transform::from(vector![0, 0, 2], vector![1, 1, 1], vector![0, 0, 0]),
+            enabled: true,
+            material: None,
+            radius: 0.5,
+            prop: PhysProp::from_color(vector![1, 0, 0]),
+        }));
+        scene
+    }
+
+    #[test]
+    fn albedo_renders_the_red_sphere_flat_red() {
+        let scene = red_sphere_scene();
+        let tracer = Tracer {
+            render_mode: RenderMode::Albedo,
+            ..Tracer::default()
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        // Straight on, and near the rim where lighting would darken it.
+        for target in [
+            vector![0, 0, 2],
+            vector![0.4, 0.2, 2],
+            vector![-0.1, -0.45, 2],
+        ] {
+            let color = tracer
+                .trace_single_ray(&scene, camera_ray(target), &mut rng)
+                .color;
+            assert!(color == vector![1, 0, 0]);
+        }
+        let sky = tracer.trace_single_ray(&scene, camera_ray(vector![2, 0, 2]), &mut rng);
+        assert!(sky.color == vector![1, 1, 1]);
+    }
+
+    #[test]
+    fn srgb_is_continuous_at_its_breakpoint() {
+        let encode = |c: Float| OutputColorSpace::Srgb.encode(vector![c, c, c])[0];
+        let breakpoint = 0.0031308;
+        // Both pieces give about 0.04045 at the breakpoint, so there is no visible step.
+        assert!((encode(breakpoint) - 0.04045).abs() < 1e-4);
+        assert!((encode(breakpoint * 1.001) - encode(breakpoint)).abs() < 1e-4);
+        // Linear below it, and a power curve above it.
+        assert!((encode(0.001) - 0.01292).abs() < 1e-6);
+        assert!((encode(0.5) - 0.7354).abs() < 1e-3);
+        assert!((encode(1.0) - 1.0).abs() < 1e-5);
+        assert_eq!(encode(-0.5), 0.0);
+        let linear = OutputColorSpace::Linear.encode(vector![0.25, 0.5, 2.0]);
+        assert!(linear == vector![0.25, 0.5, 2.0]);
+    }
+
+    #[test]
+    fn more_light_samples_smooth_a_penumbra() {
+        // A floor lit by a light straight above it, half hidden behind a smaller sphere.
+        let mut scene = Scene::empty();
+        scene.objects.push(Box::new(Plane {
+            transform: Transform::from(vector![0, 0, 0], vector![10, 10, 10], vector![-90, 0, 0]),
+            enabled: true,
+            material: None,
+            prop: PhysProp::from_color(vector![1, 1, 1]),
+            back_prop: None,
+        }));
+        scene.objects.push(Box::new(Sphere {
+            transform: Transform::from(vector![0, -2, 0], vector![1, 1, 1], vector![0, 0, 0]),
+            enabled: true,
+            material: None,
+            radius: 0.5,
+            prop: PhysProp::from_emission(vector![1, 1, 1], vector![1, 1, 1]),
+        }));
+        scene.objects.push(Box::new(Sphere {
+            transform: Transform::from(vector![0.25, -1, 0], vector![1, 1, 1], vector![0, 0, 0]),
+            enabled: true,
+            material: None,
+            radius: 0.2,
+            prop: PhysProp::from_color(vector![0, 0, 0]),
+        }));
+        scene.find_lights();
+        let ray = Ray {
+            pos: vector![0, -0.5, -0.5],
+            normal: vector![0, 0.5, 0.5].as_unit_vector(),
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut mean_and_variance = |light_samples: u16| {
+            let tracer = Tracer {
+                max_depth: 2,
+                light_samples,
+                ..Tracer::default()
+            };
+            let colors: Vec<Float> = (0..2000)
+                .map(|_| tracer.trace_single_ray(&scene, ray, &mut rng).color[0])
+                .collect();
+            let mean = colors.iter().sum::<Float>() / colors.len() as Float;
+            let variance = colors
+                .iter()
+                .map(|c| (c - mean) * (c - mean))
+                .sum::<Float>()
+                / colors.len() as Float;
+            (mean, variance)
+        };
+        let (mean1, variance1) = mean_and_variance(1);
+        let (mean16, variance16) = mean_and_variance(16);
+        // Bouncing into the light by chance still adds noise that light samples don't remove.
+        assert!(
+            mean1 > 0.0 && (mean16 / mean1 - 1.0).abs() < 0.1,
+            "{} vs {}",
+            mean16,
+            mean1
+        );
+        assert!(
+            variance16 < variance1 * 0.5,
+            "{} vs {}",
+            variance16,
+            variance1
+        );
+    }
+
+    #[test]
+    fn emitters_of_equal_power_light_a_floor_equally() {
+        // A floor lit from straight above by a sphere of the given radius and emission.
+        let floor_brightness = |radius: Float, emission: Float| {
+            let mut scene = Scene::empty();
+            scene.objects.push(Box::new(Plane {
+                transform: Transform::from(
+                    vector![0, 0, 0],
+                    vector![10, 10, 10],
+                    vector![-90, 0, 0],
+                ),
+                enabled: true,
+                material: None,
+                prop: PhysProp::from_color(vector![1, 1, 1]),
+                back_prop: None,
+            }));
+            scene.objects.push(Box::new(Sphere {
+                transform: Transform::from(vector![0, -2, 0], vector![1, 1, 1], vector![0, 0, 0]),
+                enabled: true,
+                material: None,
+                radius,
+                prop: PhysProp::from_emission(
+                    vector![1, 1, 1],
+                    vector![emission, emission, emission],
+                ),
+            }));
+            scene.find_lights();
+            let tracer = Tracer {
+                max_depth: 2,
+                ..Tracer::default()
+            };
+            let ray = Ray {
+                pos: vector![0, -0.5, -0.5],
+                normal: vector![0, 0.5, 0.5].as_unit_vector(),
+            };
+            let mut rng = StdRng::seed_from_u64(0);
+            (0..4000)
+                .map(|_| tracer.trace_single_ray(&scene, ray, &mut rng).color[0])
+                .sum::<Float>()
+                / 4000.0
+        };
+        // A quarter of the radius has a sixteenth of the area.
+        let small = floor_brightness(0.05, 100.0);
+        let large = floor_brightness(0.2, 6.25);
+        assert!(
+            small > 0.0 && (large / small - 1.0).abs() < 0.05,
+            "{} vs {}",
+            large,
+            small
+        );
+    }
+
+    // A plain image to display framebuffers on, standing in for an SDL canvas.
+    struct TestCanvas {
+        width: u16,
+        height: u16,
+        pixels: Vec<Vector<3>>,
+    }
+
+    impl TestCanvas {
+        fn new(width: u16, height: u16) -> TestCanvas {
+            TestCanvas {
+                width,
+                height,
+                pixels: vec![vector![0, 0, 0]; width as usize * height as usize],
+            }
+        }
+    }
+
+    impl Framebuffer for TestCanvas {
+        fn width(&self) -> u16 {
+            self.width
+        }
+        fn height(&self) -> u16 {
+            self.height
+        }
+        fn set_pixel(&mut self, x: u16, y: u16, col: Vector<3>) {
+            self.pixels[y as usize * self.width as usize + x as usize] = col;
+        }
+        fn get_pixel(&self, x: u16, y: u16) -> Vector<3> {
+            self.pixels[y as usize * self.width as usize + x as usize]
+        }
+    }
+
+    #[test]
+    fn merged_framebuffers_equal_one_run_for_all_frames() {
+        let (width, height) = (4, 3);
+        let frame_color =
+            |frame: u16, x: u16, y: u16| vector![frame, x, y] * 0.1 + vector![0, 0, 0.05];
+        let mut canvas = TestCanvas::new(width, height);
+        let mut render = |fb: &mut SmoothingFramebuffer, frames: std::ops::Range<u16>| {
+            for frame in frames {
+                for y in 0..height {
+                    for x in 0..width {
+                        fb.set_pixel(x, y, frame_color(frame, x, y));
+                    }
+                }
+                fb.update(&mut canvas);
+            }
+        };
+        let mut first = SmoothingFramebuffer::new(width, height);
+        let mut second = SmoothingFramebuffer::new(width, height);
+        let mut whole = SmoothingFramebuffer::new(width, height);
+        render(&mut first, 0..3);
+        render(&mut second, 3..8);
+        render(&mut whole, 0..8);
+        first.merge(&second);
+        assert_eq!(first.get_frame(), whole.get_frame());
+        for y in 0..height {
+            for x in 0..width {
+                let error = (first.get_pixel(x, y) - whole.get_pixel(x, y)).magnitude();
+                assert!(error < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn thread_count_uses_the_hint_or_the_machine() {
+        assert!(resolve_thread_count(0) >= 1);
+        if let Ok(available) = std::thread::available_parallelism() {
+            assert_eq!(resolve_thread_count(0) as usize, available.get());
+        }
+        for hint in [1, 3, 64] {
+            assert_eq!(resolve_thread_count(hint), hint);
+        }
+    }
+
+    #[test]
+    fn bokeh_samples_lie_within_their_shape() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let (x, y) = BokehShape::Disk.sample(&mut rng);
+            assert!(x * x + y * y <= 1.0 + 1e-6);
+        }
+        for blades in [5, 6] {
+            let shape = BokehShape::Polygon { blades };
+            let n = blades as Float;
+            // Distance from the center to the middle of each edge.
+            let apothem = (consts::PI / n).cos();
+            let mut past_apothem = false;
+            for _ in 0..1000 {
+                let (x, y) = shape.sample(&mut rng);
+                for edge in 0..blades {
+                    let angle = consts::TAU * (edge as Float + 0.5) / n;
+                    assert!(x * angle.cos() + y * angle.sin() <= apothem + 1e-5);
+                }
+                past_apothem |= (x * x + y * y).sqrt() > apothem;
+            }
+            // Some points reach into the corners, so it isn't just a smaller disk.
+            assert!(past_apothem);
+        }
+    }
+
+    #[test]
+    fn seeded_render_is_the_same_for_1_and_4_threads() {
+        let scene = Arc::new(red_sphere_scene());
+        let tracer = Arc::new(Tracer {
+            seed: Some(0),
+            ..Tracer::default()
+        });
+        let camera = Transform::identity();
+        let render = |threads: u16| {
+            let mut canvas = TestCanvas::new(16, 12);
+            tracer.trace_image_async(scene.clone(), &mut canvas, &camera, threads, 0);
+            canvas.pixels
+        };
+        let single = render(1);
+        assert!(compare_images(&single, &render(4), 0.0).passed());
+        // The same split by hand, giving each part its own unrelated random numbers.
+        let mut canvas = TestCanvas::new(16, 12);
+        for part in 0..4 {
+            let mut rng = StdRng::seed_from_u64(100 + part as u64);
+            let part = ImagePart {
+                interlace_amount: 4,
+                interlace_offset: part,
+                ..ImagePart::whole(16, 12, 0)
+            };
+            tracer.trace_partial_image(&scene, &mut canvas, &camera, &mut rng, part);
+        }
+        assert!(compare_images(&single, &canvas.pixels, 0.0).passed());
+    }
+
+    #[test]
+    fn wavefront_matches_tracing_pixel_by_pixel() {
+        let scene = red_sphere_scene();
+        let camera = Transform::identity();
+        let render = |wavefront: bool| {
+            let tracer = Tracer {
+                wavefront,
+                seed: Some(0),
+                ..Tracer::default()
+            };
+            let mut canvas = TestCanvas::new(16, 12);
+            let mut rng = StdRng::seed_from_u64(0);
+            tracer.trace_image(&scene, &mut canvas, &camera, &mut rng, 0);
+            canvas.pixels
+        };
+        assert!(compare_images(&render(false), &render(true), 0.0).passed());
+    }
+
+    #[test]
+    fn frame_scene_ignores_a_scene_with_everything_disabled() {
+        let mut scene = red_sphere_scene();
+        let tracer = Tracer::default();
+        let mut camera = Transform::identity();
+        tracer.frame_scene(&mut camera, &scene);
+        // The sphere's box has a radius of sqrt(3) / 2, seen through a 90 degree field of view.
+        let expected = vector![0, 0, 2.0 - (0.75 as Float).sqrt() / (0.5 as Float).sqrt()];
+        assert!((*camera.pos() - expected).magnitude() < 1e-4);
+        scene.objects[0].set_enabled(false);
+        let mut camera = Transform::identity();
+        tracer.frame_scene(&mut camera, &scene);
+        assert!(*camera.pos() == vector![0, 0, 0]);
+    }
+
+    #[test]
+    fn slice_framebuffer_writes_at_the_stride_offset() {
+        // 3 by 2 pixels with 4 bytes of padding after each row.
+        let mut data = vec![0u8; 16 * 2];
+        assert!(SliceFramebuffer::new(&mut data, 3, 2, 11).is_err());
+        assert!(SliceFramebuffer::new(&mut data[..27], 3, 2, 16).is_err());
+        let mut fb = SliceFramebuffer::new(&mut data, 3, 2, 16).unwrap();
+        fb.set_pixel(1, 1, vector![1, 0, 0]);
+        // Render just the last pixel, which looks past the sphere at the white sky.
+        let tracer = Tracer {
+            render_mode: RenderMode::Albedo,
+            ..Tracer::default()
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let camera = Transform::identity();
+        let scene = red_sphere_scene();
+        let part = ImagePart {
+            bounds: (2, 1, 1, 1),
+            ..ImagePart::whole(3, 2, 0)
+        };
+        tracer.trace_partial_image(&scene, &mut fb, &camera, &mut rng, part);
+        assert!(fb.get_pixel(2, 1) == vector![1, 1, 1]);
+        let mut expected = vec![0u8; 16 * 2];
+        expected[16 + 4..16 + 8].copy_from_slice(&[255, 0, 0, 255]);
+        expected[16 + 8..16 + 12].copy_from_slice(&[255, 255, 255, 255]);
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn smoothing_framebuffer_stretches_onto_a_bigger_canvas() {
+        let mut fb = SmoothingFramebuffer::new(100, 100);
+        for y in 0..100 {
+            for x in 0..100 {
+                fb.set_pixel(x, y, vector![x, y, 50] / 100.0);
+            }
+        }
+        let mut canvas = TestCanvas::new(200, 200);
+        fb.update(&mut canvas);
+        // Each source pixel covers 2 by 2 canvas pixels, whose centers are a quarter pixel
+        // either side of the source pixel's center.
+        let at = |x: u16, y: u16| canvas.get_pixel(x, y);
+        assert!((at(101, 60) - vector![50.25, 29.75, 50] / 100.0).magnitude() < 1e-5);
+        assert!((at(100, 61) - vector![49.75, 30.25, 50] / 100.0).magnitude() < 1e-5);
+        // Edges repeat the outermost pixels instead of reading past them.
+        assert!((at(0, 0) - vector![0, 0, 50] / 100.0).magnitude() < 1e-5);
+        assert!((at(199, 199) - vector![99, 99, 50] / 100.0).magnitude() < 1e-5);
+        // And back down to a smaller one without going out of bounds.
+        let mut small = TestCanvas::new(30, 20);
+        fb.update(&mut small);
+        assert!(small.get_pixel(29, 19)[0] > small.get_pixel(0, 19)[0]);
+    }
+
+    #[test]
+    fn trace_pixel_center_of_red_sphere_is_red() {
+        let scene = red_sphere_scene();
+        let tracer = Tracer::default();
+        let camera = Transform::identity();
+        let mut rng = StdRng::seed_from_u64(0);
+        let color = tracer
+            .trace_pixel(&scene, &camera, (8, 6), (16, 12), &mut rng, 0)
+            .color;
+        assert!(color[0] > 0.1, "{:?}", color.data());
+        assert!(color[0] > color[1] * 4.0 && color[0] > color[2] * 4.0);
+    }
+
+    #[test]
+    fn trace_pixel_background_is_the_sky() {
+        let scene = red_sphere_scene();
+        let tracer = Tracer::default();
+        let camera = Transform::identity();
+        let mut rng = StdRng::seed_from_u64(0);
+        let color = tracer
+            .trace_pixel(&scene, &camera, (0, 0), (16, 12), &mut rng, 0)
+            .color;
+        assert!(color == vector![1, 1, 1], "{:?}", color.data());
+    }
+
+    #[test]
+    fn cloned_scene_renders_identically() {
+        let mut scene = red_sphere_scene();
+        scene.objects.push(Box::new(Plane {
+            transform: Transform::from(vector![0, 0.5, 2], vector![1, 1, 1], vector![90, 0, 0]),
+            enabled: true,
+            material: None,
+            prop: PhysProp::from_color(vector![0.5, 0.5, 0.5]),
+            back_prop: None,
+        }));
+        let copy = scene.clone();
+        let tracer = Tracer::default();
+        let camera = Transform::identity();
+        let mut rng = StdRng::seed_from_u64(0);
+        for y in 0..12 {
+            for x in 0..16 {
+                let pixel = |scene: &Scene, rng: &mut StdRng| {
+                    tracer
+                        .trace_pixel(scene, &camera, (x, y), (16, 12), rng, 0)
+                        .color
+                };
+                assert!(pixel(&scene, &mut rng) == pixel(&copy, &mut rng));
+            }
+        }
+        // Changing the copy leaves the original alone.
+        let mut copy = copy;
+        copy.objects[0].set_enabled(false);
+        assert!(scene.objects[0].enabled());
+    }
+
+    #[test]
+    fn black_sky_without_lights_renders_black() {
+        let mut scene = red_sphere_scene();
+        scene.sky_model = SkyModel::Black;
+        let tracer = Tracer::default();
+        let camera = Transform::identity();
+        let mut rng = StdRng::seed_from_u64(0);
+        // Both the sphere and the sky around it, which would be white otherwise.
+        for y in 0..12 {
+            for x in 0..16 {
+                let color = tracer
+                    .trace_pixel(&scene, &camera, (x, y), (16, 12), &mut rng, 0)
+                    .color;
+                assert!(color == vector![0, 0, 0], "{:?}", color.data());
+            }
+        }
+    }
+
+    #[test]
+    fn smooth_triangle_facing_away_still_reflects() {
+        let mut scene = Scene::empty();
+        scene.skybox_color = vector![1, 1, 1];
+        scene.horizon_color = vector![1, 1, 1];
+        scene.ground_color = vector![1, 1, 1];
+        // A mirror facing the camera whose vertex normals lean away from it, as near a
+        // silhouette. It absorbs strongly, so treating the hit as leaving it turns it black.
+        let away = vector![1, 0, 0.2].as_unit_vector();
+        scene.objects.push(Box::new(Triangle {
+            transform: Transform::from(vector![0, 0, 2], vector![1, 1, 1], vector![0, 0, 0]),
+            enabled: true,
+            material: None,
+            vertices: [vector![-1, 1, 0], vector![1, 1, 0], vector![0, -1, 0]],
+            normals: Some([away, away, away]),
+            prop: PhysProp {
+                roughness: 0.0,
+                absorption: vector![100, 100, 100],
+                ..PhysProp::from_color(vector![1, 1, 1])
+            },
+        }));
+        let ray = camera_ray(vector![0.0, 1.0 / 3.0, 2.0]);
+        let hit = scene.raycast(ray).unwrap();
+        assert!(hit.normal.dot(ray.normal) > 0.0);
+        assert!(hit.is_entry);
+        let tracer = Tracer::default();
+        let mut rng = StdRng::seed_from_u64(0);
+        let color = tracer.trace_single_ray(&scene, ray, &mut rng).color;
+        assert!(color == vector![1, 1, 1], "{:?}", color.data());
+    }
+
+    #[test]
+    fn gaussian_filter_is_finite_at_the_edge_of_the_pixel() {
+        let filter = PixelFilter::Gaussian { radius: 0.5 };
+        for (x, y) in [(0.5, 0.5), (-0.5, -0.5), (0.5, -0.5), (0.0, 0.0)] {
+            let (dx, dy) = filter.warp(x, y);
+            assert!(
+                dx.is_finite() && dy.is_finite(),
+                "{} {} at {} {}",
+                dx,
+                dy,
+                x,
+                y
+            );
+        }
+    }
+
+    #[test]
+    fn irradiance_cache_blends_between_neighboring_cells() {
+        let cache = IrradianceCache::new(1.0, 1);
+        let up = vector![0, -1, 0];
+        cache.insert(vector![0.5, 0.5, 0.5], up, vector![0, 0, 0]);
+        cache.insert(vector![1.5, 0.5, 0.5], up, vector![1, 1, 1]);
+        let at = |x: Float| cache.lookup(vector![x, 0.5, 0.5], up).unwrap()[0];
+        // Constant up to the center of the cell at the end, and linear between the centers.
+        assert!(at(0.2) == 0.0 && at(1.8) == 1.0);
+        for x in [0.5, 0.75, 0.999, 1.0, 1.25, 1.5] {
+            assert!((at(x) - (x - 0.5)).abs() < 1e-4, "{} at {}", at(x), x);
+        }
+        // Neither the other side of the surface nor unsampled cells.
+        assert!(cache.lookup(vector![0.5, 0.5, 0.5], -up).is_none());
+        assert!(cache.lookup(vector![2.5, 0.5, 0.5], up).is_none());
+    }
+
+    #[test]
+    fn frame_scene_fits_two_spheres_far_apart() {
+        let mut scene = Scene::empty();
+        for center in [vector![-10, 0, 5], vector![10, 3, 20]] {
+            scene.objects.push(Box::new(Sphere {
+                transform: Transform::from(center, vector![1, 1, 1], vector![0, 0, 0]),
+                enabled: true,
+                material: None,
+                radius: 1.0,
+                prop: PhysProp::from_color(vector![1, 1, 1]),
+            }));
+        }
+        let (min, max) = scene.bounding_box().unwrap();
+        assert!((min - vector![-11, -1, 4]).magnitude() < 1e-4);
+        assert!((max - vector![11, 4, 21]).magnitude() < 1e-4);
+        let tracer = Tracer::default();
+        let mut camera = Transform::identity();
+        tracer.frame_scene(&mut camera, &scene);
+        // Each whole sphere is within half the field of view of the camera's forward axis.
+        for i in 0..2 {
+            let offset = *scene.objects[i].transform().pos() - *camera.pos();
+            let angle = offset.as_unit_vector().dot(camera.forward()).acos()
+                + (1.0 / offset.magnitude()).asin();
+            assert!(angle <= (tracer.fov * 0.5).to_radians(), "sphere {}", i);
+        }
+        // And both show up in a render.
+        let mut seen = [false, false];
+        for y in 0..32 {
+            for x in 0..32 {
+                let ray = tracer.camera_ray(&camera, x as Float + 0.5, y as Float + 0.5, 32, 32);
+                if let Some(hit) = scene.raycast(ray) {
+                    seen[hit.object_id] = true;
+                }
+            }
+        }
+        assert!(seen == [true, true]);
+    }
+
+    #[test]
+    fn furnace_conserves_energy_for_diffuse_rough_and_mirror_spheres() {
+        let tracer = Tracer {
+            render_mode: RenderMode::Furnace,
+            max_depth: 64,
+            ..Tracer::default()
+        };
+        let camera = Transform::identity();
+        let mut rng = StdRng::seed_from_u64(0);
+        for roughness in [1.0, 0.5, 0.0] {
+            let mut scene = Scene::empty();
+            scene.objects.push(Box::new(Sphere {
+                transform: Transform::from(vector![0, 0, 2], vector![1, 1, 1], vector![0, 0, 0]),
+                enabled: true,
+                material: None,
+                radius: 0.8,
+                prop: PhysProp {
+                    roughness,
+                    ..PhysProp::from_color(vector![0.2, 0.5, 0.8])
+                },
+            }));
+            let average = tracer.furnace_average(&scene, &camera, 32, 32, &mut rng);
+            assert!(
+                (average - vector![1, 1, 1]).magnitude() < 1e-3,
+                "roughness {}: {:?}",
+                roughness,
+                average.data()
+            );
+        }
+    }
 }